@@ -68,16 +68,12 @@ pub enum OpCode {
 impl OpCode {
     /// Is this a control opcode?
     pub fn is_control(self) -> bool {
-        if let OpCode::Close | OpCode::Ping | OpCode::Pong = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
     }
 
     /// Is this opcode reserved?
     pub fn is_reserved(self) -> bool {
-        match self {
+        matches! { self,
             OpCode::Reserved3
             | OpCode::Reserved4
             | OpCode::Reserved5
@@ -87,8 +83,7 @@ impl OpCode {
             | OpCode::Reserved12
             | OpCode::Reserved13
             | OpCode::Reserved14
-            | OpCode::Reserved15 => true,
-            _ => false
+            | OpCode::Reserved15
         }
     }
 }
@@ -309,6 +304,21 @@ impl Header {
         self.payload_len = len;
         self
     }
+
+    /// The size of the encoded header.
+    pub fn header_len(&self) -> usize {
+        let len = match self.payload_len {
+            n if n < usize::from(TWO_EXT) => 2,
+            n if n <= usize::from(u16::MAX) => 4,
+            _ => 10
+        };
+        if self.masked { len + 4 } else { len }
+    }
+
+    /// The size of the frame on the wire, i.e. encoded header plus payload.
+    pub fn encoded_len(&self) -> usize {
+        self.header_len() + self.payload_len
+    }
 }
 
 // Base codec ////////////////////////////////////////////////////////////////////////////////////.
@@ -330,6 +340,8 @@ pub struct Codec {
     max_data_size: usize,
     /// Bits reserved by an extension.
     reserved_bits: u8,
+    /// Reject payload lengths not encoded with the minimal number of bytes.
+    require_minimal_length: bool,
     /// Scratch buffer used during header encoding.
     header_buffer: [u8; MAX_HEADER_SIZE]
 }
@@ -339,6 +351,7 @@ impl Default for Codec {
         Codec {
             max_data_size: 256 * 1024 * 1024,
             reserved_bits: 0,
+            require_minimal_length: false,
             header_buffer: [0; MAX_HEADER_SIZE]
         }
     }
@@ -364,6 +377,15 @@ impl Codec {
         self
     }
 
+    /// Reject payload lengths which are not minimally encoded.
+    ///
+    /// RFC 6455 requires senders to use the minimal number of bytes for the
+    /// payload length, but by default we accept longer encodings.
+    pub fn set_require_minimal_length(&mut self, require: bool) -> &mut Self {
+        self.require_minimal_length = require;
+        self
+    }
+
     /// The reserved bits currently configured.
     pub fn reserved_bits(&self) -> (bool, bool, bool) {
         let r = self.reserved_bits;
@@ -392,37 +414,11 @@ impl Codec {
         let second = bytes[1];
         let mut offset = 2;
 
-        let fin = first & 0x80 != 0;
-        let opcode = OpCode::try_from(first & 0xF)?;
-
-        if opcode.is_reserved() {
-            return Err(Error::ReservedOpCode)
-        }
-
-        if opcode.is_control() && !fin {
-            return Err(Error::FragmentedControl)
-        }
-
-        let mut header = Header::new(opcode);
-        header.set_fin(fin);
-
-        let rsv1 = first & 0x40 != 0;
-        if rsv1 && (self.reserved_bits & 4 == 0) {
-            return Err(Error::InvalidReservedBit(1))
-        }
-        header.set_rsv1(rsv1);
-
-        let rsv2 = first & 0x20 != 0;
-        if rsv2 && (self.reserved_bits & 2 == 0) {
-            return Err(Error::InvalidReservedBit(2))
-        }
-        header.set_rsv2(rsv2);
-
-        let rsv3 = first & 0x10 != 0;
-        if rsv3 && (self.reserved_bits & 1 == 0) {
-            return Err(Error::InvalidReservedBit(3))
-        }
-        header.set_rsv3(rsv3);
+        let mut header = Header::new(OpCode::try_from(first & 0xF)?);
+        header.set_fin(first & 0x80 != 0);
+        header.set_rsv1(first & 0x40 != 0);
+        header.set_rsv2(first & 0x20 != 0);
+        header.set_rsv3(first & 0x10 != 0);
         header.set_masked(second & 0x80 != 0);
 
         let len: u64 = match second & 0x7F {
@@ -432,6 +428,9 @@ impl Codec {
                 }
                 let len = u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
                 offset += 2;
+                if self.require_minimal_length && len < u16::from(TWO_EXT) {
+                    return Err(Error::NonMinimalLength)
+                }
                 u64::from(len)
             }
             EIGHT_EXT => {
@@ -441,26 +440,26 @@ impl Codec {
                 let mut b = [0; 8];
                 b.copy_from_slice(&bytes[offset .. offset + 8]);
                 offset += 8;
-                u64::from_be_bytes(b)
+                let len = u64::from_be_bytes(b);
+                if self.require_minimal_length && len <= u64::from(u16::MAX) {
+                    return Err(Error::NonMinimalLength)
+                }
+                len
             }
             n => u64::from(n)
         };
 
-        if len > MAX_CTRL_BODY_SIZE && header.opcode().is_control() {
-            return Err(Error::InvalidControlFrameLen)
-        }
-
-        let len: usize =
-            if len > as_u64(self.max_data_size) {
-                return Err(Error::PayloadTooLarge {
-                    actual: len,
-                    maximum: as_u64(self.max_data_size)
-                })
-            } else {
-                len as usize
-            };
+        // Validate before checking the maximum size, which may be less than
+        // the 125 bytes allowed for control frames.
+        header.set_payload_len(usize::try_from(len).unwrap_or(usize::MAX));
+        self.validate_header(&header)?;
 
-        header.set_payload_len(len);
+        if len > as_u64(self.max_data_size) {
+            return Err(Error::PayloadTooLarge {
+                actual: len,
+                maximum: as_u64(self.max_data_size)
+            })
+        }
 
         if header.is_masked() {
             if bytes.len() < offset + 4 {
@@ -475,6 +474,35 @@ impl Codec {
         Ok(Parsing::Done { value: header, offset })
     }
 
+    /// Check that a header satisfies the structural invariants of RFC 6455.
+    ///
+    /// Reserved opcodes are rejected, control frames must not be fragmented
+    /// and carry at most 125 bytes of payload, and only the reserved bits
+    /// configured via `add_reserved_bits` may be set. Whether frames of a
+    /// message are in order cannot be checked from a single header.
+    pub fn validate_header(&self, header: &Header) -> Result<(), Error> {
+        let opcode = header.opcode();
+        if opcode.is_reserved() {
+            return Err(Error::ReservedOpCode)
+        }
+        if opcode.is_control() && !header.is_fin() {
+            return Err(Error::FragmentedControl)
+        }
+        if opcode.is_control() && as_u64(header.payload_len()) > MAX_CTRL_BODY_SIZE {
+            return Err(Error::InvalidControlFrameLen)
+        }
+        if header.is_rsv1() && (self.reserved_bits & 4 == 0) {
+            return Err(Error::InvalidReservedBit(1))
+        }
+        if header.is_rsv2() && (self.reserved_bits & 2 == 0) {
+            return Err(Error::InvalidReservedBit(2))
+        }
+        if header.is_rsv3() && (self.reserved_bits & 1 == 0) {
+            return Err(Error::InvalidReservedBit(3))
+        }
+        Ok(())
+    }
+
     /// Encode a websocket frame header.
     pub fn encode_header(&mut self, header: &Header) -> &[u8] {
         let mut offset = 0;
@@ -510,7 +538,7 @@ impl Codec {
             second_byte |= len as u8;
             self.header_buffer[offset] = second_byte;
             offset += 1;
-        } else if len <= usize::from(u16::max_value()) {
+        } else if len <= usize::from(u16::MAX) {
             second_byte |= TWO_EXT;
             self.header_buffer[offset] = second_byte;
             offset += 1;
@@ -535,11 +563,23 @@ impl Codec {
     /// Use the given header's mask and apply it to the data.
     pub fn apply_mask(header: &Header, data: &mut [u8]) {
         if header.is_masked() {
-            let mask = header.mask().to_be_bytes();
-            for (byte, &key) in data.iter_mut().zip(mask.iter().cycle()) {
-                *byte ^= key;
+            Codec::unmask_in_place(data, header.mask().to_be_bytes())
+        }
+    }
+
+    /// Unmask the data in place with the given masking key.
+    ///
+    /// As masking is its own inverse, this masks unmasked data, too.
+    pub fn unmask_in_place(data: &mut [u8], key: [u8; 4]) {
+        let mut chunks = data.chunks_exact_mut(4);
+        for chunk in &mut chunks {
+            for (byte, k) in chunk.iter_mut().zip(key.iter()) {
+                *byte ^= k
             }
         }
+        for (byte, k) in chunks.into_remainder().iter_mut().zip(key.iter()) {
+            *byte ^= k
+        }
     }
 }
 
@@ -560,7 +600,9 @@ pub enum Error {
     /// The reserved bit is invalid.
     InvalidReservedBit(u8),
     /// The payload length of a frame exceeded the configured maximum.
-    PayloadTooLarge { actual: u64, maximum: u64 }
+    PayloadTooLarge { actual: u64, maximum: u64 },
+    /// The payload length was not encoded with the minimal number of bytes.
+    NonMinimalLength
 }
 
 impl fmt::Display for Error {
@@ -579,7 +621,9 @@ impl fmt::Display for Error {
             Error::InvalidReservedBit(n) =>
                 write!(f, "invalid reserved bit: {}", n),
             Error::PayloadTooLarge { actual, maximum } =>
-                write!(f, "payload too large: len = {}, maximum = {}", actual, maximum)
+                write!(f, "payload too large: len = {}, maximum = {}", actual, maximum),
+            Error::NonMinimalLength =>
+                f.write_str("payload length not minimally encoded")
         }
     }
 }
@@ -594,6 +638,7 @@ impl std::error::Error for Error {
             | Error::InvalidControlFrameLen
             | Error::InvalidReservedBit(_)
             | Error::PayloadTooLarge {..}
+            | Error::NonMinimalLength
             => None
         }
     }
@@ -618,7 +663,7 @@ impl From<UnknownOpCode> for Error {
 mod test {
     use crate::Parsing;
     use quickcheck::QuickCheck;
-    use super::{OpCode, Codec, Error};
+    use super::{OpCode, Codec, Error, Header};
 
     #[test]
     fn decode_partial_header() {
@@ -658,7 +703,7 @@ mod test {
         if let Ok(Parsing::Done { value, offset }) = Codec::new().decode_header(partial_payload) {
             assert_eq!(3, value.payload_len() - (partial_payload.len() - offset))
         } else {
-            assert!(false)
+            panic!()
         }
     }
 
@@ -672,6 +717,20 @@ mod test {
         })
     }
 
+    #[test]
+    fn control_frame_len_is_checked_before_max_size() {
+        let mut codec = Codec::new();
+        codec.set_max_data_size(64);
+        assert!(matches! {
+            codec.decode_header(&[0x89, 0x7E, 0x00, 0x7E]),
+            Err(Error::InvalidControlFrameLen)
+        });
+        assert!(matches! {
+            codec.decode_header(&[0x89, 0x7D]),
+            Err(Error::PayloadTooLarge { actual: 125, maximum: 64 })
+        })
+    }
+
     /// Checking that rsv1, rsv2, and rsv3 bit set returns error.
     #[test]
     fn decode_reserved() {
@@ -727,7 +786,7 @@ mod test {
             assert!(header.opcode() == OpCode::Ping);
             assert!(header.payload_len() == 0)
         } else {
-            assert!(false)
+            panic!()
         }
     }
 
@@ -741,5 +800,161 @@ mod test {
         }
         QuickCheck::new().quickcheck(property as fn((bool, bool, bool)) -> bool)
     }
-}
 
+    #[test]
+    fn decode_mask() {
+        let masked: &[u8] = &[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        if let Ok(Parsing::Done { value: header, offset }) = Codec::new().decode_header(masked) {
+            assert!(header.is_masked());
+            assert_eq!(0x37fa_213d, header.mask());
+            assert_eq!(6, offset)
+        } else {
+            panic!()
+        }
+        let unmasked: &[u8] = &[0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        if let Ok(Parsing::Done { value: header, .. }) = Codec::new().decode_header(unmasked) {
+            assert!(!header.is_masked());
+            assert_eq!(0, header.mask())
+        } else {
+            panic!()
+        }
+    }
+
+    #[test]
+    fn encode_new_header() {
+        let mut codec = Codec::new();
+        let mut header = Header::new(OpCode::Text);
+        header.set_payload_len(5);
+        assert_eq!(&[0x81, 0x05], codec.encode_header(&header));
+        assert_eq!(&[0x89, 0x00], codec.encode_header(&Header::new(OpCode::Ping)));
+        header.set_opcode(OpCode::Binary).set_payload_len(256);
+        assert_eq!(&[0x82, 0x7E, 0x01, 0x00], codec.encode_header(&header))
+    }
+
+    #[test]
+    fn decode_consecutive_frames() {
+        let bytes: &[u8] = &[0x89, 0x00, 0x81, 0x02, b'h', b'i'];
+        let codec = Codec::new();
+        if let Ok(Parsing::Done { value: header, offset }) = codec.decode_header(bytes) {
+            assert_eq!(OpCode::Ping, header.opcode());
+            assert_eq!(0, header.payload_len());
+            assert_eq!(2, offset);
+            if let Ok(Parsing::Done { value: header, offset }) = codec.decode_header(&bytes[offset ..]) {
+                assert_eq!(OpCode::Text, header.opcode());
+                assert!(header.is_fin());
+                assert_eq!(2, header.payload_len());
+                assert_eq!(2, offset)
+            } else {
+                panic!()
+            }
+        } else {
+            panic!()
+        }
+    }
+
+    #[test]
+    fn validate_header() {
+        let codec = Codec::new();
+        assert!(codec.validate_header(&Header::new(OpCode::Text)).is_ok());
+
+        let mut h = Header::new(OpCode::Reserved3);
+        assert!(matches!(codec.validate_header(&h), Err(Error::ReservedOpCode)));
+
+        h = Header::new(OpCode::Ping);
+        h.set_fin(false);
+        assert!(matches!(codec.validate_header(&h), Err(Error::FragmentedControl)));
+
+        h = Header::new(OpCode::Close);
+        h.set_payload_len(125);
+        assert!(codec.validate_header(&h).is_ok());
+        h.set_payload_len(126);
+        assert!(matches!(codec.validate_header(&h), Err(Error::InvalidControlFrameLen)));
+
+        h = Header::new(OpCode::Binary);
+        h.set_rsv2(true);
+        assert!(matches!(codec.validate_header(&h), Err(Error::InvalidReservedBit(2))));
+        let mut codec = Codec::new();
+        codec.add_reserved_bits((false, true, false));
+        assert!(codec.validate_header(&h).is_ok());
+        h.set_rsv3(true);
+        assert!(matches!(codec.validate_header(&h), Err(Error::InvalidReservedBit(3))))
+    }
+
+    #[test]
+    fn unmask_in_place() {
+        let key = [0x37, 0xfa, 0x21, 0x3d];
+        for len in 0 .. 11 {
+            let data: Vec<u8> = (0 .. len).map(|i: u8| i.wrapping_mul(31)).collect();
+            let expected: Vec<u8> = data.iter().enumerate().map(|(i, b)| b ^ key[i % 4]).collect();
+            let mut actual = data.clone();
+            Codec::unmask_in_place(&mut actual, key);
+            assert_eq!(expected, actual);
+            Codec::unmask_in_place(&mut actual, key);
+            assert_eq!(data, actual)
+        }
+    }
+
+    #[test]
+    fn decode_payload_too_large() {
+        // Announces a payload of 2^63 bytes, no payload data follows.
+        let header: &[u8] = &[0x82, 0x7F, 0x80, 0, 0, 0, 0, 0, 0, 0];
+        assert!(matches! {
+            Codec::new().decode_header(header),
+            Err(Error::PayloadTooLarge { actual, .. }) if actual == 1 << 63
+        });
+        let mut codec = Codec::new();
+        codec.set_max_data_size(125);
+        assert!(matches!(codec.decode_header(&[0x82, 0x7D]), Ok(Parsing::Done { .. })));
+        assert!(matches! {
+            codec.decode_header(&[0x82, 0x7E, 0x00, 0x7E]),
+            Err(Error::PayloadTooLarge { actual: 126, maximum: 125 })
+        })
+    }
+
+    #[test]
+    fn encoded_len() {
+        let mut codec = Codec::new();
+        for &(len, expected) in &[(0, 2), (125, 2), (126, 4), (65535, 4), (65536, 10)] {
+            for &masked in &[false, true] {
+                let mut header = Header::new(OpCode::Binary);
+                header.set_payload_len(len).set_masked(masked);
+                let expected = if masked { expected + 4 } else { expected };
+                assert_eq!(expected, header.header_len());
+                assert_eq!(codec.encode_header(&header).len(), header.header_len());
+                assert_eq!(expected + len, header.encoded_len())
+            }
+        }
+    }
+
+    #[test]
+    fn decode_truncated_after_length_indicator() {
+        // 126 needs two more length bytes, 127 needs eight.
+        let two: &[u8] = &[0x82, 0x7E, 0x01, 0x00];
+        let eight: &[u8] = &[0x82, 0x7F, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00];
+        for &(frame, more, len) in &[(two, 2, 256), (eight, 8, 65536)] {
+            let codec = Codec::new();
+            assert!(matches!(codec.decode_header(&frame[.. 2]), Ok(Parsing::NeedMore(n)) if n == more));
+            assert!(matches!(codec.decode_header(&frame[.. 3]), Ok(Parsing::NeedMore(n)) if n == more - 1));
+            match codec.decode_header(frame) {
+                Ok(Parsing::Done { value, offset }) => {
+                    assert_eq!(len, value.payload_len());
+                    assert_eq!(frame.len(), offset)
+                }
+                _ => panic!("header not decoded")
+            }
+        }
+    }
+
+    #[test]
+    fn decode_non_minimal_length() {
+        let two: &[u8] = &[0x82, 0x7E, 0x00, 0x7D];
+        let eight: &[u8] = &[0x82, 0x7F, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF];
+        let mut codec = Codec::new();
+        assert!(matches!(codec.decode_header(two), Ok(Parsing::Done { .. })));
+        assert!(matches!(codec.decode_header(eight), Ok(Parsing::Done { .. })));
+        codec.set_require_minimal_length(true);
+        assert!(matches!(codec.decode_header(two), Err(Error::NonMinimalLength)));
+        assert!(matches!(codec.decode_header(eight), Err(Error::NonMinimalLength)));
+        assert!(matches!(codec.decode_header(&[0x82, 0x7E, 0x00, 0x7E]), Ok(Parsing::Done { .. })))
+    }
+}
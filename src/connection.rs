@@ -10,10 +10,10 @@
 //! as a [`Sender`] and [`Receiver`] pair.
 
 use bytes::{Buf, BytesMut};
-use crate::{Storage, Parsing, base::{self, Header, MAX_HEADER_SIZE, OpCode}, extension::Extension};
-use crate::data::{ByteSlice125, Data, Incoming};
-use futures::{io::{ReadHalf, WriteHalf}, lock::BiLock, prelude::*};
-use std::{fmt, io, str};
+use crate::{Storage, Parsing, base::{self, Header, MAX_HEADER_SIZE, OpCode}, extension::{Extension, PeerError}};
+use crate::data::{ByteSlice125, Data, Incoming, Message, Outgoing};
+use futures::{io::{ReadHalf, WriteHalf}, lock::BiLock, prelude::*, stream};
+use std::{fmt, io, mem, str, sync::{Arc, Mutex, atomic::{AtomicU8, AtomicUsize, Ordering}}, time::{Duration, Instant}};
 
 /// Accumulated max. size of a complete message.
 const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
@@ -21,6 +21,12 @@ const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
 /// Max. size of a single message frame.
 const MAX_FRAME_SIZE: usize = MAX_MESSAGE_SIZE;
 
+/// Close state bit: we have sent a close message.
+const CLOSE_SENT: u8 = 1;
+
+/// Close state bit: the remote has sent a close message.
+const CLOSE_RECEIVED: u8 = 2;
+
 /// Is the connection used by a client or server?
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Mode {
@@ -32,11 +38,7 @@ pub enum Mode {
 
 impl Mode {
     pub fn is_client(self) -> bool {
-        if let Mode::Client = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Mode::Client)
     }
 
     pub fn is_server(self) -> bool {
@@ -44,6 +46,346 @@ impl Mode {
     }
 }
 
+/// How a [`Receiver`] treats text messages which are not valid UTF-8.
+///
+/// Only [`Utf8Policy::Strict`] conforms to RFC 6455, which requires the
+/// connection to fail on invalid UTF-8 text.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// Close the connection with status code 1007 (invalid payload data).
+    Strict,
+    /// Replace invalid sequences with U+FFFD (non-conformant).
+    Lossy,
+    /// Silently discard the message (non-conformant).
+    Skip
+}
+
+/// What a [`Receiver`] does with messages exceeding its [`RateLimit`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RateLimitAction {
+    /// Close the connection with status code 1008 (policy violation).
+    Close,
+    /// Silently discard the message.
+    Drop
+}
+
+/// Limits the rate of messages a [`Receiver`] accepts.
+///
+/// Each limit is enforced with a token bucket which allows bursts of up
+/// to one second worth of messages or bytes.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    messages: Option<u32>,
+    bytes: Option<u64>,
+    action: RateLimitAction
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            messages: None,
+            bytes: None,
+            action: RateLimitAction::Close
+        }
+    }
+}
+
+impl RateLimit {
+    /// Create a new rate limit which does not limit anything yet.
+    pub fn new() -> Self {
+        RateLimit::default()
+    }
+
+    /// Limit the number of messages per second.
+    pub fn set_messages_per_second(&mut self, n: u32) -> &mut Self {
+        self.messages = Some(n);
+        self
+    }
+
+    /// Limit the number of payload bytes per second.
+    pub fn set_bytes_per_second(&mut self, n: u64) -> &mut Self {
+        self.bytes = Some(n);
+        self
+    }
+
+    /// Set what to do with messages exceeding the limit (default: close).
+    pub fn set_action(&mut self, a: RateLimitAction) -> &mut Self {
+        self.action = a;
+        self
+    }
+}
+
+/// A handle to observe the reassembly of a fragmented message.
+///
+/// Obtained from [`Receiver::progress`], it can be queried while the
+/// receiver is busy receiving, e.g. to report progress of large uploads.
+#[derive(Clone, Debug)]
+pub struct Progress {
+    /// Number of bytes reassembled so far plus one, or 0 if idle.
+    current: Arc<AtomicUsize>,
+    maximum: usize
+}
+
+impl Progress {
+    /// The number of bytes reassembled so far and the maximum message size.
+    ///
+    /// Returns `None` unless a fragmented message is being received.
+    pub fn get(&self) -> Option<(usize, usize)> {
+        match self.current.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some((n - 1, self.maximum))
+        }
+    }
+}
+
+/// A part of a message received with [`Receiver::receive_chunk`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    data: Data,
+    is_last: bool
+}
+
+impl Chunk {
+    /// The type of the message and the number of bytes in this chunk.
+    pub fn data(&self) -> &Data {
+        &self.data
+    }
+
+    /// Is this the final chunk of the message?
+    pub fn is_last(&self) -> bool {
+        self.is_last
+    }
+}
+
+/// A frame which is being received with [`Receiver::receive_chunk`].
+#[derive(Debug)]
+struct Partial {
+    /// The opcode of the message this frame belongs to.
+    opcode: OpCode,
+    header: Header,
+    /// The number of payload bytes of the frame not yet received.
+    remaining: usize,
+    /// The number of payload bytes of the frame received so far.
+    offset: usize,
+    /// The number of payload bytes of the message received so far.
+    length: usize,
+    /// Is the message discarded because it exceeds the rate limit?
+    discard: bool,
+    /// Validates text messages with [`Utf8Policy::Strict`].
+    utf8: Option<Utf8Validator>
+}
+
+/// Validates UTF-8 text which is received in arbitrary chunks.
+#[derive(Debug, Default)]
+struct Utf8Validator {
+    /// The bytes of a code point split across chunks.
+    pending: [u8; 4],
+    /// The number of bytes in `pending`.
+    len: usize
+}
+
+impl Utf8Validator {
+    /// Validate the next chunk of text, which is the last if `is_last` is set.
+    fn feed(&mut self, mut data: &[u8], is_last: bool) -> Result<(), str::Utf8Error> {
+        if self.len > 0 {
+            let width = match self.pending[0] {
+                0xF0 ..= 0xF7 => 4,
+                0xE0 ..= 0xEF => 3,
+                _ => 2
+            };
+            let n = std::cmp::min(width - self.len, data.len());
+            self.pending[self.len .. self.len + n].copy_from_slice(&data[.. n]);
+            self.len += n;
+            data = &data[n ..];
+            if self.len < width {
+                return self.finish(is_last)
+            }
+            str::from_utf8(&self.pending[.. width])?;
+            self.len = 0
+        }
+        match str::from_utf8(data) {
+            Ok(_) => Ok(()),
+            Err(e) if e.error_len().is_none() => {
+                let tail = &data[e.valid_up_to() ..];
+                self.pending[.. tail.len()].copy_from_slice(tail);
+                self.len = tail.len();
+                self.finish(is_last)
+            }
+            Err(e) => Err(e)
+        }
+    }
+
+    /// At the end of the text, a pending code point is incomplete and invalid.
+    fn finish(&self, is_last: bool) -> Result<(), str::Utf8Error> {
+        if is_last {
+            str::from_utf8(&self.pending[.. self.len])?;
+        }
+        Ok(())
+    }
+}
+
+/// The state of the close handshake, shared by sender and receiver.
+#[derive(Debug, Default)]
+struct CloseState {
+    /// `CLOSE_SENT` and `CLOSE_RECEIVED` bits.
+    flags: AtomicU8,
+    /// When we have sent our close message.
+    sent_at: Mutex<Option<Instant>>
+}
+
+impl CloseState {
+    fn is_sent(&self) -> bool {
+        self.flags.load(Ordering::Acquire) & CLOSE_SENT != 0
+    }
+
+    fn is_closing(&self) -> bool {
+        self.flags.load(Ordering::Acquire) != 0
+    }
+
+    fn is_closed(&self) -> bool {
+        self.flags.load(Ordering::Acquire) == CLOSE_SENT | CLOSE_RECEIVED
+    }
+
+    /// Record that we have sent a close message.
+    fn set_sent(&self) {
+        if self.flags.fetch_or(CLOSE_SENT, Ordering::AcqRel) & CLOSE_SENT == 0 {
+            if let Ok(mut sent_at) = self.sent_at.lock() {
+                *sent_at = Some(Instant::now())
+            }
+        }
+    }
+
+    /// Record that the connection has been aborted without a close handshake.
+    fn set_aborted(&self) {
+        self.flags.fetch_or(CLOSE_SENT | CLOSE_RECEIVED, Ordering::AcqRel);
+    }
+
+    /// Record that the remote has sent a close message.
+    ///
+    /// Returns `true` if we had sent a close message before.
+    fn set_received(&self) -> bool {
+        self.flags.fetch_or(CLOSE_RECEIVED, Ordering::AcqRel) & CLOSE_SENT != 0
+    }
+
+    fn sent_at(&self) -> Option<Instant> {
+        self.sent_at.lock().ok().and_then(|t| *t)
+    }
+
+    fn deadline_passed(&self, timeout: Duration, now: Instant) -> bool {
+        match self.sent_at() {
+            Some(t) => !self.is_closed() && now.saturating_duration_since(t) >= timeout,
+            None => false
+        }
+    }
+}
+
+/// The PINGs we have sent, shared by sender and receiver.
+#[derive(Debug, Default)]
+struct PingState {
+    /// The number of PINGs not answered yet.
+    outstanding: AtomicUsize,
+    /// The payload of the last PING sent.
+    payload: Mutex<Vec<u8>>
+}
+
+impl PingState {
+    fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    /// Record that we have sent a PING with the given payload.
+    fn on_ping(&self, data: &[u8]) {
+        if let Ok(mut payload) = self.payload.lock() {
+            payload.clear();
+            payload.extend_from_slice(data)
+        }
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that the remote has sent a PONG with the given payload.
+    ///
+    /// Only a PONG echoing the last PING answers it, which acknowledges
+    /// all outstanding PINGs (cf. RFC 6455, 5.5.3). Other PONGs, e.g.
+    /// unsolicited ones, do not count.
+    fn on_pong(&self, data: &[u8]) {
+        if self.payload.lock().map(|p| *p == data).unwrap_or(false) {
+            self.outstanding.store(0, Ordering::Relaxed)
+        }
+    }
+}
+
+/// A token bucket refilled with `rate` tokens per second.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Bucket { rate, tokens: rate, updated: now }
+    }
+
+    /// Refill the bucket as of `now` and try to take `n` tokens from it.
+    ///
+    /// Amounts larger than the bucket's capacity are accepted if the bucket
+    /// is full and leave it in debt, so a single large message gets through
+    /// but delays subsequent ones.
+    fn take(&mut self, n: f64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens <= 0.0 || self.tokens < f64::min(n, self.rate) {
+            return false
+        }
+        self.tokens -= n;
+        true
+    }
+
+    /// Refill the bucket as of `now` and take `n` tokens, even if it goes into debt.
+    fn charge(&mut self, n: f64, now: Instant) {
+        self.refill(now);
+        self.tokens -= n
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = f64::min(self.rate, self.tokens + elapsed * self.rate);
+        self.updated = now
+    }
+}
+
+/// The state of a [`RateLimit`] applied to incoming messages.
+#[derive(Debug)]
+struct RateLimiter {
+    messages: Option<Bucket>,
+    bytes: Option<Bucket>,
+    action: RateLimitAction
+}
+
+impl RateLimiter {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        RateLimiter {
+            messages: limit.messages.map(|n| Bucket::new(f64::from(n), now)),
+            bytes: limit.bytes.map(|n| Bucket::new(n as f64, now)),
+            action: limit.action
+        }
+    }
+
+    /// Account for a message of the given size and check if it is within limits.
+    fn admit(&mut self, len: usize, now: Instant) -> bool {
+        let m = self.messages.as_mut().map(|b| b.take(1.0, now)).unwrap_or(true);
+        let b = self.bytes.as_mut().map(|b| b.take(len as f64, now)).unwrap_or(true);
+        m && b
+    }
+
+    /// Account for more bytes of a message which has already been admitted.
+    fn charge(&mut self, len: usize, now: Instant) {
+        if let Some(b) = &mut self.bytes {
+            b.charge(len as f64, now)
+        }
+    }
+}
+
 /// Connection ID.
 #[derive(Clone, Copy, Debug)]
 struct Id(u32);
@@ -63,7 +405,11 @@ pub struct Sender<T> {
     writer: BiLock<WriteHalf<T>>,
     mask_buffer: Vec<u8>,
     extensions: BiLock<Vec<Box<dyn Extension + Send>>>,
-    has_extensions: bool
+    has_extensions: bool,
+    pings: Arc<PingState>,
+    max_outstanding_pings: Option<usize>,
+    close_state: Arc<CloseState>,
+    auto_flush: bool
 }
 
 /// The receiving half of a connection.
@@ -78,8 +424,19 @@ pub struct Receiver<T> {
     has_extensions: bool,
     buffer: BytesMut,
     ctrl_buffer: BytesMut,
+    message_buffer: Vec<u8>,
     max_message_size: usize,
-    is_closed: bool
+    is_closed: bool,
+    /// Has the remote sent a close message (held in `ctrl_buffer`)?
+    close_received: bool,
+    pings: Arc<PingState>,
+    close_state: Arc<CloseState>,
+    progress: Arc<AtomicUsize>,
+    partial: Option<Partial>,
+    /// Scratch buffer for the payload passed to extensions.
+    decode_buffer: Vec<u8>,
+    rate_limiter: Option<RateLimiter>,
+    utf8_policy: Option<Utf8Policy>
 }
 
 /// A connection builder.
@@ -95,7 +452,11 @@ pub struct Builder<T> {
     codec: base::Codec,
     extensions: Vec<Box<dyn Extension + Send>>,
     buffer: BytesMut,
-    max_message_size: usize
+    max_message_size: usize,
+    max_outstanding_pings: Option<usize>,
+    rate_limit: Option<RateLimit>,
+    utf8_policy: Option<Utf8Policy>,
+    auto_flush: bool
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
@@ -117,19 +478,24 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
             codec,
             extensions: Vec::new(),
             buffer: BytesMut::new(),
-            max_message_size: MAX_MESSAGE_SIZE
+            max_message_size: MAX_MESSAGE_SIZE,
+            max_outstanding_pings: None,
+            rate_limit: None,
+            utf8_policy: None,
+            auto_flush: true
         }
     }
 
     /// Set a custom buffer to use.
-    pub fn set_buffer(&mut self, b: BytesMut) {
-        self.buffer = b
+    pub fn set_buffer(&mut self, b: BytesMut) -> &mut Self {
+        self.buffer = b;
+        self
     }
 
     /// Add extensions to use with this connection.
     ///
     /// Only enabled extensions will be considered.
-    pub fn add_extensions<I>(&mut self, extensions: I)
+    pub fn add_extensions<I>(&mut self, extensions: I) -> &mut Self
     where
         I: IntoIterator<Item = Box<dyn Extension + Send>>
     {
@@ -138,6 +504,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
             self.codec.add_reserved_bits(e.reserved_bits());
             self.extensions.push(e)
         }
+        self
     }
 
     /// Set the maximum size of a complete message.
@@ -146,21 +513,106 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
     /// i.e. the sum of all message frames payload lengths will not be greater
     /// than this maximum. However, extensions may increase the total message
     /// size further, e.g. by decompressing the payload data.
-    pub fn set_max_message_size(&mut self, max: usize) {
-        self.max_message_size = max
+    pub fn set_max_message_size(&mut self, max: usize) -> &mut Self {
+        self.max_message_size = max;
+        self
     }
 
     /// Set the maximum size of a single websocket frame payload.
-    pub fn set_max_frame_size(&mut self, max: usize) {
+    pub fn set_max_frame_size(&mut self, max: usize) -> &mut Self {
         self.codec.set_max_data_size(max);
+        self
+    }
+
+    /// Reject frames whose payload length is not minimally encoded.
+    ///
+    /// See [`base::Codec::set_require_minimal_length`]. Disabled by default.
+    pub fn set_require_minimal_length(&mut self, require: bool) -> &mut Self {
+        self.codec.set_require_minimal_length(require);
+        self
+    }
+
+    /// Apply the settings which conform most strictly to RFC 6455.
+    ///
+    /// Text messages are validated with [`Utf8Policy::Strict`] and
+    /// non-minimal payload lengths are rejected. Masking, reserved bits and
+    /// control frames are always validated. Settings applied afterwards
+    /// override individual choices.
+    pub fn strict(&mut self) -> &mut Self {
+        self.set_utf8_policy(Utf8Policy::Strict).set_require_minimal_length(true)
+    }
+
+    /// Apply settings which tolerate common deviations of peers.
+    ///
+    /// Text messages are not validated and non-minimal payload lengths are
+    /// accepted, which are the defaults. Protocol violations which would
+    /// make frames ambiguous are still rejected.
+    pub fn lenient(&mut self) -> &mut Self {
+        self.utf8_policy = None;
+        self.set_require_minimal_length(false)
+    }
+
+    /// Set the maximum number of PINGs awaiting a PONG.
+    ///
+    /// If the remote does not answer, [`Sender::send_ping`] fails with
+    /// [`Error::UnansweredPings`] once this many PINGs are outstanding and
+    /// the connection is closed with status code 1011.
+    ///
+    /// A PONG answers a PING if it carries the same payload as the last
+    /// PING sent, which acknowledges all PINGs sent so far. Other PONGs do
+    /// not count, hence a ping payload unique to each PING, e.g. a counter,
+    /// gives the most reliable results.
+    ///
+    /// By default there is no limit: PONGs are only noticed while the
+    /// [`Receiver`] is receiving, so any default would fail connections of
+    /// applications which send PINGs but read rarely, and only the
+    /// application knows how often it sends PINGs and reads.
+    pub fn set_max_outstanding_pings(&mut self, max: usize) -> &mut Self {
+        self.max_outstanding_pings = Some(max);
+        self
+    }
+
+    /// Limit the rate of incoming messages.
+    ///
+    /// Messages exceeding the limit are dropped or cause the connection to
+    /// be closed with [`Error::RateLimitExceeded`], depending on the
+    /// [`RateLimitAction`]. By default there is no limit.
+    pub fn set_rate_limit(&mut self, limit: RateLimit) -> &mut Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Validate the UTF-8 encoding of incoming text messages.
+    ///
+    /// By default text messages are not validated and it is up to the user
+    /// to check their encoding.
+    pub fn set_utf8_policy(&mut self, policy: Utf8Policy) -> &mut Self {
+        self.utf8_policy = Some(policy);
+        self
+    }
+
+    /// Flush every text or binary frame right after it has been sent.
+    ///
+    /// This is enabled by default, giving the lowest latency. Disabling it
+    /// allows a buffered socket to coalesce several frames into fewer writes,
+    /// which improves throughput, but then [`Sender::flush`] must be called to
+    /// actually send buffered frames. Control frames are always flushed.
+    pub fn set_auto_flush(&mut self, auto_flush: bool) -> &mut Self {
+        self.auto_flush = auto_flush;
+        self
     }
 
     /// Create a configured [`Sender`]/[`Receiver`] pair.
-    pub fn finish(self) -> (Sender<T>, Receiver<T>) {
+    pub fn finish(mut self) -> (Sender<T>, Receiver<T>) {
+        for e in self.extensions.iter_mut() {
+            e.set_max_message_size(self.max_message_size)
+        }
         let (rhlf, whlf) = self.socket.split();
         let (wrt1, wrt2) = BiLock::new(whlf);
         let has_extensions = !self.extensions.is_empty();
         let (ext1, ext2) = BiLock::new(self.extensions);
+        let pings = Arc::new(PingState::default());
+        let close_state = Arc::new(CloseState::default());
 
         let recv = Receiver {
             id: self.id,
@@ -172,8 +624,17 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
             has_extensions,
             buffer: self.buffer,
             ctrl_buffer: BytesMut::new(),
+            message_buffer: Vec::new(),
             max_message_size: self.max_message_size,
-            is_closed: false
+            is_closed: false,
+            close_received: false,
+            pings: pings.clone(),
+            close_state: close_state.clone(),
+            progress: Arc::new(AtomicUsize::new(0)),
+            partial: None,
+            decode_buffer: Vec::new(),
+            rate_limiter: self.rate_limit.as_ref().map(|l| RateLimiter::new(l, Instant::now())),
+            utf8_policy: self.utf8_policy
         };
 
         let send = Sender {
@@ -183,7 +644,11 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
             mask_buffer: Vec::new(),
             codec: self.codec,
             extensions: ext2,
-            has_extensions
+            has_extensions,
+            pings,
+            max_outstanding_pings: self.max_outstanding_pings,
+            close_state,
+            auto_flush: self.auto_flush
         };
 
         (send, recv)
@@ -201,19 +666,54 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
     /// values. If PONGs are not expected or uninteresting,
     /// [`Receiver::receive_data`] may be used instead which skips over PONGs
     /// and considers only application payload data.
+    ///
+    /// If the remote violates the protocol or an error occurs while
+    /// processing a frame, the connection is closed with the status code
+    /// given by [`Error::close_code`].
     pub async fn receive(&mut self, message: &mut Vec<u8>) -> Result<Incoming<'_>, Error> {
+        let result = self.receive_message(message).await;
+        self.progress.store(0, Ordering::Relaxed);
+        match result {
+            Ok(Some(data)) => Ok(Incoming::Data(data)),
+            Ok(None) => Ok(Incoming::Pong(&self.ctrl_buffer[..])),
+            Err(e) => {
+                self.close_on_error(&e).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Close the connection with the status code of the given error, if any.
+    async fn close_on_error(&mut self, e: &Error) {
+        if let Some(code) = e.close_code() {
+            if !self.is_closed && !self.is_closing() {
+                log::debug!("{}: closing connection with {} because of: {}", self.id, code, e);
+                if let Err(e) = self.close_with(code).await {
+                    log::debug!("{}: failed to close connection: {}", self.id, e)
+                }
+            }
+        }
+    }
+
+    /// Receive the next message, returning `None` for PONGs (held in `ctrl_buffer`).
+    async fn receive_message(&mut self, message: &mut Vec<u8>) -> Result<Option<Data>, Error> {
         let mut first_fragment_opcode = None;
         let mut length: usize = 0;
         let message_len = message.len();
-        loop {
-            if self.is_closed {
+        let mut utf8_valid = message_len; // end of the text known to be valid UTF-8
+        'receive: loop {
+            if self.is_closed || self.close_state.is_closed() {
                 log::debug!("{}: can not receive, connection is closed", self.id);
                 return Err(Error::Closed)
             }
 
             self.ctrl_buffer.clear();
             let mut header = self.receive_header().await?;
-            log::trace!("{}: recv: {}", self.id, header);
+            match first_fragment_opcode {
+                Some(oc) if header.opcode() == OpCode::Continue =>
+                    log::trace!("{}: recv: {} (of {})", self.id, header, oc),
+                _ => log::trace!("{}: recv: {}", self.id, header)
+            }
 
             // Handle control frames.
             if header.opcode().is_control() {
@@ -221,7 +721,8 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
                 self.ctrl_buffer = self.buffer.split_to(header.payload_len());
                 base::Codec::apply_mask(&header, &mut self.ctrl_buffer);
                 if header.opcode() == OpCode::Pong {
-                    return Ok(Incoming::Pong(&self.ctrl_buffer[..]))
+                    self.pings.on_pong(&self.ctrl_buffer);
+                    return Ok(None)
                 }
                 self.on_control(&header).await?;
                 continue
@@ -236,8 +737,9 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
             }
 
             // Get the frame's payload data bytes from buffer or socket.
+            let frame_start = message.len();
             {
-                let old_msg_len = message.len();
+                let old_msg_len = frame_start;
 
                 let bytes_to_read = {
                     let required = header.payload_len();
@@ -266,12 +768,25 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
                 base::Codec::apply_mask(&header, &mut message[old_msg_len ..]);
             }
 
+            // The remote may send data until it has seen our close message
+            // (RFC 6455, 1.4), which is discarded while we wait for its answer.
+            if self.close_state.is_sent() {
+                log::trace!("{}: discarding data frame, close message has been sent", self.id);
+                message.truncate(message_len);
+                first_fragment_opcode = None;
+                length = 0;
+                continue
+            }
+
             match (header.is_fin(), header.opcode()) {
                 (false, OpCode::Continue) => { // Intermediate message fragment.
                     if first_fragment_opcode.is_none() {
                         log::debug!("{}: continue frame while not processing message fragments", self.id);
                         return Err(Error::UnexpectedOpCode(OpCode::Continue))
                     }
+                    self.decode_with_extensions(&mut header, message, frame_start).await?;
+                    self.check_utf8_fragment(first_fragment_opcode, message, &mut utf8_valid)?;
+                    self.progress.store(length + 1, Ordering::Relaxed);
                     continue
                 }
                 (false, oc) => { // Initial message fragment.
@@ -280,14 +795,16 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
                         return Err(Error::UnexpectedOpCode(oc))
                     }
                     first_fragment_opcode = Some(oc);
-                    self.decode_with_extensions(&mut header, message).await?;
+                    utf8_valid = message_len;
+                    self.decode_with_extensions(&mut header, message, frame_start).await?;
+                    self.check_utf8_fragment(first_fragment_opcode, message, &mut utf8_valid)?;
+                    self.progress.store(length + 1, Ordering::Relaxed);
                     continue
                 }
                 (true, OpCode::Continue) => { // Last message fragment.
                     if let Some(oc) = first_fragment_opcode.take() {
-                        header.set_payload_len(message.len());
-                        log::trace!("{}: last fragment: total length = {} bytes", self.id, message.len());
-                        self.decode_with_extensions(&mut header, message).await?;
+                        log::trace!("{}: last fragment: total length = {} bytes", self.id, length);
+                        self.decode_with_extensions(&mut header, message, frame_start).await?;
                         header.set_opcode(oc);
                     } else {
                         log::debug!("{}: last continue frame while not processing message fragments", self.id);
@@ -299,16 +816,61 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
                         log::debug!("{}: regular message while processing fragmented message", self.id);
                         return Err(Error::UnexpectedOpCode(oc))
                     }
-                    self.decode_with_extensions(&mut header, message).await?
+                    utf8_valid = message_len;
+                    self.decode_with_extensions(&mut header, message, frame_start).await?
+                }
+            }
+
+            // Extensions may have enlarged the message, e.g. by decompressing it.
+            if message.len() - message_len > self.max_message_size {
+                log::warn!("{}: decoded message length exceeds maximum", self.id);
+                let current = message.len() - message_len;
+                message.truncate(message_len);
+                return Err(Error::MessageTooLarge { current, maximum: self.max_message_size })
+            }
+
+            if header.opcode() == OpCode::Text {
+                if let Some(policy) = self.utf8_policy {
+                    let from = if policy == Utf8Policy::Strict { utf8_valid } else { message_len };
+                    if let Err(e) = str::from_utf8(&message[from ..]) {
+                        log::debug!("{}: invalid utf-8 text: {}", self.id, e);
+                        match policy {
+                            Utf8Policy::Strict => return Err(Error::Utf8(e)),
+                            Utf8Policy::Lossy => {
+                                let text = String::from_utf8_lossy(&message[message_len ..]).into_owned();
+                                message.truncate(message_len);
+                                message.extend_from_slice(text.as_bytes())
+                            }
+                            Utf8Policy::Skip => {
+                                message.truncate(message_len);
+                                length = 0;
+                                continue 'receive
+                            }
+                        }
+                    }
                 }
             }
 
             let num_bytes = message.len() - message_len;
 
+            if let Some(limiter) = &mut self.rate_limiter {
+                if !limiter.admit(num_bytes, Instant::now()) {
+                    log::debug!("{}: message exceeds rate limit", self.id);
+                    match limiter.action {
+                        RateLimitAction::Drop => {
+                            message.truncate(message_len);
+                            length = 0;
+                            continue 'receive
+                        }
+                        RateLimitAction::Close => return Err(Error::RateLimitExceeded)
+                    }
+                }
+            }
+
             if header.opcode() == OpCode::Text {
-                return Ok(Incoming::Data(Data::Text(num_bytes)))
+                return Ok(Some(Data::Text(num_bytes)))
             } else {
-                return Ok(Incoming::Data(Data::Binary(num_bytes)))
+                return Ok(Some(Data::Binary(num_bytes)))
             }
         }
     }
@@ -322,12 +884,236 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
         }
     }
 
+    /// Receive the next websocket message into an internal buffer.
+    ///
+    /// Like [`Receiver::receive_data`], but instead of appending to a
+    /// caller provided vector, the payload is returned as a slice of a
+    /// buffer owned by this `Receiver`. The buffer is reused for every
+    /// message, so once its capacity suffices no more allocations are
+    /// necessary. The borrow ends with the next call to any of the
+    /// `receive` methods, so the payload needs to be copied if it should
+    /// be kept around.
+    pub async fn receive_data_ref(&mut self) -> Result<(Data, &[u8]), Error> {
+        let mut message = mem::take(&mut self.message_buffer);
+        message.clear();
+        let result = self.receive_data(&mut message).await;
+        self.message_buffer = message;
+        Ok((result?, &self.message_buffer))
+    }
+
+    /// Get a handle to observe the reassembly of fragmented messages.
+    pub fn progress(&self) -> Progress {
+        Progress { current: self.progress.clone(), maximum: self.max_message_size }
+    }
+
+    /// Turn this receiver into a stream of complete messages.
+    ///
+    /// Text messages are validated and returned as `String`s, binary
+    /// messages as `Bytes`. PINGs are answered as usual and PONGs skipped.
+    /// The stream ends when the connection is closed or after the first
+    /// error.
+    pub fn into_stream(self) -> impl Stream<Item = Result<Message, Error>> {
+        stream::unfold(Some(self), |receiver| async move {
+            let mut receiver = receiver?;
+            let mut message = Vec::new();
+            match receiver.receive_data(&mut message).await {
+                Ok(Data::Binary(_)) => Some((Ok(Message::Binary(message.into())), Some(receiver))),
+                Ok(Data::Text(_)) => match String::from_utf8(message) {
+                    Ok(text) => Some((Ok(Message::Text(text)), Some(receiver))),
+                    Err(e) => {
+                        if let Err(e) = receiver.close_with(1007).await {
+                            log::debug!("{}: failed to close connection: {}", receiver.id, e)
+                        }
+                        Some((Err(Error::Utf8(e.utf8_error())), None))
+                    }
+                }
+                Err(Error::Closed) => None,
+                Err(e) => Some((Err(e), None))
+            }
+        })
+    }
+
+    /// Receive the next chunk of at most `max` bytes of a message.
+    ///
+    /// Unlike [`Receiver::receive`], frames are not buffered as a whole.
+    /// Payload data is appended to `chunk` as soon as it is available and
+    /// [`Chunk::is_last`] marks the end of the message, so that large
+    /// messages can be processed incrementally. Control frames are handled
+    /// as usual and PONGs are skipped.
+    ///
+    /// Chunks are not decoded by extensions, hence this fails with
+    /// [`Error::Extension`] if extensions are in use. With
+    /// [`Utf8Policy::Strict`], text is validated chunk by chunk and invalid
+    /// UTF-8 fails the connection as soon as it is received. The other UTF-8
+    /// policies act on complete messages and can not be applied to chunks,
+    /// hence this fails with an [`Error::Io`] of kind `InvalidInput` if
+    /// they are set, as it does for a `max` of 0.
+    ///
+    /// A [`RateLimit`] is checked once per message, when its first frame
+    /// arrives. Further frames of an admitted message are not rejected, but
+    /// count towards the limit of bytes for subsequent messages. Messages
+    /// dropped because of the rate limit are skipped without returning any
+    /// chunks.
+    ///
+    /// A message must be received completely, either with `receive_chunk`
+    /// or [`Receiver::receive`], before switching to the other.
+    pub async fn receive_chunk(&mut self, chunk: &mut Vec<u8>, max: usize) -> Result<Chunk, Error> {
+        if self.has_extensions {
+            return Err(Error::Extension("extensions can not decode chunks".into()))
+        }
+        if max == 0 {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "chunk size must not be 0")))
+        }
+        if matches!(self.utf8_policy, Some(Utf8Policy::Lossy) | Some(Utf8Policy::Skip)) {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "utf-8 policy requires complete messages")))
+        }
+        let result = self.receive_chunk_inner(chunk, max).await;
+        if let Err(e) = &result {
+            self.close_on_error(e).await
+        }
+        result
+    }
+
+    async fn receive_chunk_inner(&mut self, chunk: &mut Vec<u8>, max: usize) -> Result<Chunk, Error> {
+        loop {
+            // Read frame headers until we have a frame with payload left to read.
+            while !matches!(&self.partial, Some(p) if p.remaining > 0) {
+                if self.is_closed || self.close_state.is_closed() {
+                    log::debug!("{}: can not receive, connection is closed", self.id);
+                    return Err(Error::Closed)
+                }
+
+                self.ctrl_buffer.clear();
+                let header = self.receive_header().await?;
+                log::trace!("{}: recv: {}", self.id, header);
+
+                if header.opcode().is_control() {
+                    self.read_buffer(&header).await?;
+                    self.ctrl_buffer = self.buffer.split_to(header.payload_len());
+                    base::Codec::apply_mask(&header, &mut self.ctrl_buffer);
+                    if header.opcode() == OpCode::Pong {
+                        self.pings.on_pong(&self.ctrl_buffer)
+                    } else {
+                        self.on_control(&header).await?
+                    }
+                    continue
+                }
+
+                if self.close_state.is_sent() {
+                    log::trace!("{}: discarding data frame, close message has been sent", self.id);
+                    self.read_buffer(&header).await?;
+                    self.buffer.advance(header.payload_len());
+                    self.partial = None;
+                    continue
+                }
+
+                let (opcode, length, discard, utf8) = match (self.partial.take(), header.opcode()) {
+                    (Some(p), OpCode::Continue) => {
+                        if let (false, Some(limiter)) = (p.discard, &mut self.rate_limiter) {
+                            limiter.charge(header.payload_len(), Instant::now())
+                        }
+                        (p.opcode, p.length, p.discard, p.utf8)
+                    }
+                    (None, OpCode::Continue) => {
+                        log::debug!("{}: continue frame while not processing message fragments", self.id);
+                        return Err(Error::UnexpectedOpCode(OpCode::Continue))
+                    }
+                    (Some(_), oc) => {
+                        log::debug!("{}: new message while processing fragmented message", self.id);
+                        return Err(Error::UnexpectedOpCode(oc))
+                    }
+                    (None, oc) => {
+                        let mut discard = false;
+                        if let Some(limiter) = &mut self.rate_limiter {
+                            if !limiter.admit(header.payload_len(), Instant::now()) {
+                                log::debug!("{}: message exceeds rate limit", self.id);
+                                match limiter.action {
+                                    RateLimitAction::Drop => discard = true,
+                                    RateLimitAction::Close => return Err(Error::RateLimitExceeded)
+                                }
+                            }
+                        }
+                        let strict = self.utf8_policy == Some(Utf8Policy::Strict);
+                        let utf8 = if oc == OpCode::Text && strict { Some(Utf8Validator::default()) } else { None };
+                        (oc, 0, discard, utf8)
+                    }
+                };
+
+                let length = length.saturating_add(header.payload_len());
+                if length > self.max_message_size {
+                    log::warn!("{}: accumulated message length exceeds maximum", self.id);
+                    return Err(Error::MessageTooLarge { current: length, maximum: self.max_message_size })
+                }
+
+                let remaining = header.payload_len();
+                let is_last = header.is_fin();
+                self.partial = Some(Partial { opcode, header, remaining, offset: 0, length, discard, utf8 });
+
+                if remaining == 0 && is_last {
+                    break
+                }
+            }
+
+            let partial = self.partial.as_mut().expect("partial frame has been set above");
+            let n = std::cmp::min(max, partial.remaining);
+            let start = chunk.len();
+            if !self.buffer.is_empty() {
+                let n = std::cmp::min(n, self.buffer.len());
+                chunk.extend_from_slice(&self.buffer.split_to(n))
+            } else if n > 0 {
+                chunk.resize(start + n, 0);
+                let k = self.reader.read(&mut chunk[start ..]).await?;
+                chunk.truncate(start + k);
+                if k == 0 {
+                    return Err(Error::Closed)
+                }
+            }
+            let n = chunk.len() - start;
+
+            if partial.header.is_masked() {
+                let mut key = partial.header.mask().to_be_bytes();
+                key.rotate_left(partial.offset % 4);
+                base::Codec::unmask_in_place(&mut chunk[start ..], key)
+            }
+            partial.remaining -= n;
+            partial.offset += n;
+
+            let is_last = partial.remaining == 0 && partial.header.is_fin();
+            if partial.discard {
+                chunk.truncate(start);
+                if is_last {
+                    self.partial = None
+                }
+                continue
+            }
+            if let Some(utf8) = &mut partial.utf8 {
+                if let Err(e) = utf8.feed(&chunk[start ..], is_last) {
+                    log::debug!("{}: invalid utf-8 text chunk: {}", self.id, e);
+                    return Err(Error::Utf8(e))
+                }
+            }
+
+            let data = if partial.opcode == OpCode::Text { Data::Text(n) } else { Data::Binary(n) };
+            if is_last {
+                self.partial = None
+            }
+            return Ok(Chunk { data, is_last })
+        }
+    }
+
     /// Read the next frame header.
     async fn receive_header(&mut self) -> Result<Header, Error> {
         loop {
             match self.codec.decode_header(&self.buffer)? {
                 Parsing::Done { value: header, offset } => {
                     debug_assert!(offset <= MAX_HEADER_SIZE);
+                    // Clients must mask their frames and servers must not (RFC 6455, 5.1).
+                    // We only get here once the complete header, including the mask key,
+                    // has been received.
+                    if header.is_masked() != (self.mode == Mode::Server) {
+                        log::debug!("{}: invalid masking: {}", self.id, header);
+                        return Err(Error::InvalidMasking)
+                    }
                     self.buffer.advance(offset);
                     return Ok(header)
                 }
@@ -363,8 +1149,14 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
             }
             OpCode::Pong => Ok(()),
             OpCode::Close => {
-                self.is_closed = true;
                 let (mut header, code) = close_answer(&self.ctrl_buffer)?;
+                self.is_closed = true;
+                self.close_received = true;
+                if self.close_state.set_received() {
+                    log::trace!("{}: close handshake complete", self.id);
+                    return Ok(())
+                }
+                self.close_state.set_sent();
                 let mut unused = Vec::new();
                 if let Some(c) = code {
                     let mut data = c.to_be_bytes();
@@ -393,18 +1185,133 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
         }
     }
 
-    /// Apply all extensions to the given header and the internal message buffer.
-    async fn decode_with_extensions(&mut self, header: &mut Header, message: &mut Vec<u8>) -> Result<(), Error> {
+    /// Validate the UTF-8 of a fragmented text message as its fragments arrive.
+    ///
+    /// This is only done for [`Utf8Policy::Strict`] and without extensions,
+    /// which may still transform the data (e.g. decompress it) at the end.
+    /// A code point split across fragments is incomplete, not invalid, and
+    /// `valid` keeps track of how much of the message has been validated.
+    fn check_utf8_fragment(&self, opcode: Option<OpCode>, message: &[u8], valid: &mut usize) -> Result<(), Error> {
+        if opcode != Some(OpCode::Text) || self.utf8_policy != Some(Utf8Policy::Strict) || self.has_extensions {
+            return Ok(())
+        }
+        match str::from_utf8(&message[*valid ..]) {
+            Ok(_) => *valid = message.len(),
+            Err(e) if e.error_len().is_none() => *valid += e.valid_up_to(),
+            Err(e) => {
+                log::debug!("{}: invalid utf-8 text fragment: {}", self.id, e);
+                return Err(Error::Utf8(e))
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply all extensions to the given header and the frame payload.
+    ///
+    /// The frame payload starts at `offset`, anything before belongs to
+    /// previous frames or the caller and is not passed to the extensions.
+    async fn decode_with_extensions(&mut self, header: &mut Header, message: &mut Vec<u8>, offset: usize) -> Result<(), Error> {
         if !self.has_extensions {
             return Ok(())
         }
-        for e in self.extensions.lock().await.iter_mut() {
+        if offset == 0 {
+            return self.apply_extensions(header, message).await
+        }
+        let mut payload = mem::take(&mut self.decode_buffer);
+        payload.clear();
+        payload.extend_from_slice(&message[offset ..]);
+        message.truncate(offset);
+        let result = self.apply_extensions(header, &mut payload).await;
+        if result.is_ok() {
+            message.extend_from_slice(&payload)
+        }
+        self.decode_buffer = payload;
+        result
+    }
+
+    /// Split off the extension data of the frame payload and decode the rest.
+    async fn apply_extensions(&mut self, header: &mut Header, payload: &mut Vec<u8>) -> Result<(), Error> {
+        let mut extensions = self.extensions.lock().await;
+        let mut start: usize = 0;
+        for e in extensions.iter_mut() {
+            let end = start.saturating_add(e.extension_data_len(header));
+            if end == start {
+                continue
+            }
+            if end > payload.len() {
+                log::debug!("{}: frame too short for extension data of {}", self.id, e.name());
+                return Err(Error::Extension(Box::new(PeerError::Protocol("missing extension data".into()))))
+            }
+            e.decode_extension_data(header, &payload[start .. end]).map_err(Error::Extension)?;
+            start = end
+        }
+        if start > 0 {
+            payload.drain(.. start);
+            header.set_payload_len(payload.len());
+        }
+        for e in extensions.iter_mut() {
             log::trace!("{}: decoding with extension: {}", self.id, e.name());
-            e.decode(header, message).map_err(Error::Extension)?
+            e.decode(header, payload).map_err(Error::Extension)?
         }
         Ok(())
     }
 
+    /// The status code and reason of the close message the remote sent, if any.
+    ///
+    /// Once [`Receiver::receive`] returned [`Error::Closed`] in answer to a
+    /// close message, this gives access to its payload.
+    pub fn close_status(&self) -> Option<(u16, &str)> {
+        if !self.close_received {
+            return None
+        }
+        parse_close(&self.ctrl_buffer).ok().flatten()
+    }
+
+    /// Has a close message been sent or received?
+    ///
+    /// Once we have sent a close message, data messages the remote sent before
+    /// it received ours are discarded until its close message arrives, and
+    /// [`Receiver::receive`] returns [`Error::Closed`]. Nothing is read after
+    /// the remote's close message.
+    pub fn is_closing(&self) -> bool {
+        self.close_state.is_closing()
+    }
+
+    /// Have both sides sent a close message?
+    ///
+    /// The close handshake is then complete and the socket may be dropped.
+    pub fn is_closed(&self) -> bool {
+        self.close_state.is_closed()
+    }
+
+    /// When we have sent a close message, if we have.
+    pub fn close_sent_at(&self) -> Option<Instant> {
+        self.close_state.sent_at()
+    }
+
+    /// Has the remote failed to answer our close message within `timeout`?
+    ///
+    /// This only compares `now` against the time our close message was sent
+    /// and does not wait. It is up to the caller to check this periodically
+    /// and to drop the connection once the deadline has passed.
+    pub fn close_deadline_passed(&self, timeout: Duration, now: Instant) -> bool {
+        self.close_state.deadline_passed(timeout, now)
+    }
+
+    /// Send a close message with the given status code and close the connection.
+    async fn close_with(&mut self, code: u16) -> Result<(), Error> {
+        log::trace!("{}: closing connection", self.id);
+        self.close_state.set_sent();
+        let mut header = Header::new(OpCode::Close);
+        let mut code = code.to_be_bytes();
+        let mut unused = Vec::new();
+        let mut data = Storage::Unique(&mut code);
+        write(self.id, self.mode, &mut self.codec, &mut self.writer, &mut header, &mut data, &mut unused).await?;
+        self.flush().await?;
+        self.is_closed = true;
+        self.writer.lock().await.close().await.or(Err(Error::Closed))
+    }
+
     /// Flush the socket buffer.
     async fn flush(&mut self) -> Result<(), Error> {
         log::trace!("{}: flushing connection", self.id);
@@ -419,13 +1326,15 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
     /// Send a text value over the websocket connection.
     pub async fn send_text(&mut self, data: impl AsRef<str>) -> Result<(), Error> {
         let mut header = Header::new(OpCode::Text);
-        self.send_frame(&mut header, &mut Storage::Shared(data.as_ref().as_bytes())).await
+        self.send_frame(&mut header, &mut Storage::Shared(data.as_ref().as_bytes())).await?;
+        self.auto_flush().await
     }
 
     /// Send some binary data over the websocket connection.
     pub async fn send_binary(&mut self, data: impl AsRef<[u8]>) -> Result<(), Error> {
         let mut header = Header::new(OpCode::Binary);
-        self.send_frame(&mut header, &mut Storage::Shared(data.as_ref())).await
+        self.send_frame(&mut header, &mut Storage::Shared(data.as_ref())).await?;
+        self.auto_flush().await
     }
 
     /// Send some binary data over the websocket connection.
@@ -434,19 +1343,165 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
     /// in-place, e.g. if masking is necessary.
     pub async fn send_binary_mut(&mut self, mut data: impl AsMut<[u8]>) -> Result<(), Error> {
         let mut header = Header::new(OpCode::Binary);
-        self.send_frame(&mut header, &mut Storage::Unique(data.as_mut())).await
+        self.send_frame(&mut header, &mut Storage::Unique(data.as_mut())).await?;
+        self.auto_flush().await
     }
 
     /// Ping the remote end.
+    ///
+    /// See [`Builder::set_max_outstanding_pings`] for limiting the number
+    /// of unanswered PINGs.
     pub async fn send_ping(&mut self, data: ByteSlice125<'_>) -> Result<(), Error> {
+        if let Some(max) = self.max_outstanding_pings {
+            if self.pings.outstanding() >= max {
+                log::debug!("{}: {} pings have not been answered", self.id, max);
+                self.close_with(1011).await?; // 1011 = internal error
+                return Err(Error::UnansweredPings)
+            }
+        }
         let mut header = Header::new(OpCode::Ping);
-        self.write(&mut header, &mut Storage::Shared(data.as_ref())).await
+        self.write(&mut header, &mut Storage::Shared(data.as_ref())).await?;
+        self.pings.on_ping(data.as_ref());
+        self.flush().await
     }
 
     /// Send an unsolicited Pong to the remote.
     pub async fn send_pong(&mut self, data: ByteSlice125<'_>) -> Result<(), Error> {
         let mut header = Header::new(OpCode::Pong);
-        self.write(&mut header, &mut Storage::Shared(data.as_ref())).await
+        self.write(&mut header, &mut Storage::Shared(data.as_ref())).await?;
+        self.flush().await
+    }
+
+    /// Send text or binary data which has already been masked with `mask`.
+    ///
+    /// This is meant for relays which forward client frames and want to
+    /// keep the original masking key: the payload is written as is, without
+    /// masking it a second time. No extensions are applied to the data.
+    ///
+    /// Only clients mask their frames, hence this fails with
+    /// [`Error::InvalidMasking`] in server mode.
+    pub async fn send_premasked(&mut self, opcode: OpCode, mask: u32, data: impl AsRef<[u8]>) -> Result<(), Error> {
+        if self.mode.is_server() {
+            return Err(Error::InvalidMasking)
+        }
+        if opcode != OpCode::Text && opcode != OpCode::Binary {
+            return Err(Error::UnexpectedOpCode(opcode))
+        }
+        let mut header = Header::new(opcode);
+        header.set_masked(true);
+        header.set_mask(mask);
+        header.set_payload_len(data.as_ref().len());
+
+        log::trace!("{}: send: {}", self.id, header);
+
+        self.ensure_open()?;
+        let header_bytes = self.codec.encode_header(&header);
+        {
+            let mut w = self.writer.lock().await;
+            w.write_all(header_bytes).await.or(Err(Error::Closed))?;
+            w.write_all(data.as_ref()).await.or(Err(Error::Closed))?
+        }
+        self.auto_flush().await
+    }
+
+    /// Send text or binary data which has already been compressed.
+    ///
+    /// This allows sending static content compressed once with
+    /// permessage-deflate, without compressing it again on each send.
+    /// `data` must be a raw deflate stream ending in a sync flush, with the
+    /// trailing `00 00 FF FF` removed (cf. RFC 7692, 7.2.1). The frame is
+    /// sent with RSV1 set, which requires an extension using RSV1 to have
+    /// been negotiated.
+    pub async fn send_precompressed(&mut self, opcode: OpCode, data: impl AsRef<[u8]>) -> Result<(), Error> {
+        if opcode != OpCode::Text && opcode != OpCode::Binary {
+            return Err(Error::UnexpectedOpCode(opcode))
+        }
+        if !self.codec.reserved_bits().0 {
+            return Err(Error::Extension("no extension using RSV1 has been negotiated".into()))
+        }
+        let mut header = Header::new(opcode);
+        header.set_rsv1(true);
+        self.send_frame(&mut header, &mut Storage::Shared(data.as_ref())).await?;
+        self.auto_flush().await
+    }
+
+    /// Send a single text or binary frame of `len` bytes read from `reader`.
+    ///
+    /// The payload is streamed in chunks, so it never needs to be held in
+    /// memory as a whole. No extensions are applied to the data. If `reader`
+    /// fails or ends before `len` bytes have been read, the frame can not be
+    /// completed. The connection is then aborted like with [`Sender::abort`]
+    /// and all further sending and receiving fails with [`Error::Closed`].
+    pub async fn send_from_reader<R>(&mut self, opcode: OpCode, len: usize, mut reader: R) -> Result<(), Error>
+    where
+        R: AsyncRead + Unpin
+    {
+        const CHUNK_SIZE: usize = 8 * 1024; // must be a multiple of 4 to keep the mask aligned
+
+        if opcode != OpCode::Text && opcode != OpCode::Binary {
+            return Err(Error::UnexpectedOpCode(opcode))
+        }
+        let mut header = Header::new(opcode);
+        if self.mode.is_client() {
+            header.set_masked(true);
+            header.set_mask(rand::random());
+        }
+        header.set_payload_len(len);
+
+        log::trace!("{}: send: {}", self.id, header);
+
+        self.ensure_open()?;
+        let mut w = self.writer.lock().await;
+        let mut result = w.write_all(self.codec.encode_header(&header)).await.or(Err(Error::Closed));
+        let mut remaining = len;
+        while result.is_ok() && remaining > 0 {
+            let n = std::cmp::min(CHUNK_SIZE, remaining);
+            self.mask_buffer.resize(n, 0);
+            if let Err(e) = reader.read_exact(&mut self.mask_buffer).await {
+                result = Err(e.into());
+                break
+            }
+            base::Codec::apply_mask(&header, &mut self.mask_buffer);
+            result = w.write_all(&self.mask_buffer).await.or(Err(Error::Closed));
+            remaining -= n
+        }
+        drop(w);
+
+        // Part of the frame may have been sent, which can not be undone.
+        if let Err(e) = result {
+            log::debug!("{}: incomplete frame, aborting connection: {}", self.id, e);
+            self.close_state.set_aborted();
+            let _ = self.writer.lock().await.close().await;
+            return Err(e)
+        }
+        self.auto_flush().await
+    }
+
+    /// Send all given messages and flush the socket buffer once at the end.
+    ///
+    /// Text and binary data are written back to back without flushing in
+    /// between, regardless of [`Builder::set_auto_flush`]. PING and PONG
+    /// frames are flushed right away, together with everything written
+    /// before them, so they are not held back by the remainder of the batch.
+    pub async fn send_all<'a, I>(&mut self, messages: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = Outgoing<'a>>
+    {
+        for m in messages {
+            match m {
+                Outgoing::Text(data) => {
+                    let mut header = Header::new(OpCode::Text);
+                    self.send_frame(&mut header, &mut Storage::Shared(data.as_bytes())).await?
+                }
+                Outgoing::Binary(data) => {
+                    let mut header = Header::new(OpCode::Binary);
+                    self.send_frame(&mut header, &mut Storage::Shared(data)).await?
+                }
+                Outgoing::Ping(data) => self.send_ping(data).await?,
+                Outgoing::Pong(data) => self.send_pong(data).await?
+            }
+        }
+        self.flush().await
     }
 
     /// Flush the socket buffer.
@@ -455,12 +1510,91 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
         self.writer.lock().await.flush().await.or(Err(Error::Closed))
     }
 
+    /// Flush the socket buffer if auto-flushing is enabled.
+    async fn auto_flush(&mut self) -> Result<(), Error> {
+        if self.auto_flush {
+            self.flush().await?
+        }
+        Ok(())
+    }
+
     /// Send a close message and close the connection.
     pub async fn close(&mut self) -> Result<(), Error> {
+        self.close_with(1000).await // 1000 = normal closure
+    }
+
+    /// Send a close message with status code and reason and close the connection.
+    ///
+    /// Like all control frames, a close frame is limited to 125 bytes, which
+    /// leaves at most 123 bytes for `reason`. Longer reasons are rejected with
+    /// [`Error::ControlFrameTooLarge`] before anything is sent.
+    pub async fn close_with_reason(&mut self, code: u16, reason: &str) -> Result<(), Error> {
+        let mut data = Vec::with_capacity(2 + reason.len());
+        encode_close(code, reason, &mut data)?;
+        self.close_with_data(&data).await
+    }
+
+    /// Close the connection immediately, without a close handshake.
+    ///
+    /// Unlike [`Sender::close`], no close message is sent and we do not
+    /// wait for the remote to answer. The underlying socket is shut down
+    /// right away, so this should only be used when a graceful close is not
+    /// wanted, e.g. after a protocol or security violation. Afterwards, both
+    /// halves of the connection are closed and fail with [`Error::Closed`].
+    pub async fn abort(&mut self) -> Result<(), Error> {
+        log::trace!("{}: aborting connection", self.id);
+        self.close_state.set_aborted();
+        self.writer.lock().await.close().await.or(Err(Error::Closed))
+    }
+
+    /// Send a close message with the given status code and close the connection.
+    async fn close_with(&mut self, code: u16) -> Result<(), Error> {
+        self.close_with_data(&code.to_be_bytes()).await
+    }
+
+    /// Has a close message been sent or received?
+    ///
+    /// Once we have sent a close message, sending fails with [`Error::Closed`].
+    pub fn is_closing(&self) -> bool {
+        self.close_state.is_closing()
+    }
+
+    /// Have both sides sent a close message?
+    ///
+    /// The close handshake is then complete and the socket may be dropped.
+    pub fn is_closed(&self) -> bool {
+        self.close_state.is_closed()
+    }
+
+    /// When we have sent a close message, if we have.
+    pub fn close_sent_at(&self) -> Option<Instant> {
+        self.close_state.sent_at()
+    }
+
+    /// Has the remote failed to answer our close message within `timeout`?
+    ///
+    /// This only compares `now` against the time our close message was sent
+    /// and does not wait. It is up to the caller to check this periodically
+    /// and to drop the connection once the deadline has passed.
+    pub fn close_deadline_passed(&self, timeout: Duration, now: Instant) -> bool {
+        self.close_state.deadline_passed(timeout, now)
+    }
+
+    /// Fail with [`Error::Closed`] if we have sent a close message already.
+    fn ensure_open(&self) -> Result<(), Error> {
+        if self.close_state.is_sent() {
+            log::debug!("{}: can not send, close message has been sent", self.id);
+            return Err(Error::Closed)
+        }
+        Ok(())
+    }
+
+    /// Send a close message with the given payload and close the connection.
+    async fn close_with_data(&mut self, data: &[u8]) -> Result<(), Error> {
         log::trace!("{}: closing connection", self.id);
         let mut header = Header::new(OpCode::Close);
-        let code = 1000_u16.to_be_bytes(); // 1000 = normal closure
-        self.write(&mut header, &mut Storage::Shared(&code[..])).await?;
+        self.write(&mut header, &mut Storage::Shared(data)).await?;
+        self.close_state.set_sent();
         self.flush().await?;
         self.writer.lock().await.close().await.or(Err(Error::Closed))
     }
@@ -486,6 +1620,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
     /// The data will be masked if necessary.
     /// No extensions will be applied to header and payload data.
     async fn write(&mut self, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
+        self.ensure_open()?;
         write(self.id, self.mode, &mut self.codec, &mut self.writer, header, data, &mut self.mask_buffer).await
     }
 }
@@ -504,14 +1639,22 @@ async fn write<T: AsyncWrite + Unpin>
     if mode.is_client() {
         header.set_masked(true);
         header.set_mask(rand::random());
+    } else if header.is_masked() {
+        log::debug!("{}: server frames must not be masked", id);
+        return Err(Error::InvalidMasking)
     }
     header.set_payload_len(data.as_ref().len());
+    if header.opcode().is_control() && header.payload_len() > 125 {
+        log::debug!("{}: control frame payload too large: {}", id, header);
+        return Err(Error::ControlFrameTooLarge(header.payload_len()))
+    }
+    codec.validate_header(header)?;
 
     log::trace!("{}: send: {}", id, header);
 
-    let header_bytes = codec.encode_header(&header);
+    let header_bytes = codec.encode_header(header);
     let mut w = writer.lock().await;
-    w.write_all(&header_bytes).await.or(Err(Error::Closed))?;
+    w.write_all(header_bytes).await.or(Err(Error::Closed))?;
 
     if !header.is_masked() {
         return w.write_all(data.as_ref()).await.or(Err(Error::Closed))
@@ -538,18 +1681,54 @@ async fn write<T: AsyncWrite + Unpin>
 /// Create a close frame based on the given data.
 fn close_answer(data: &[u8]) -> Result<(Header, Option<u16>), Error> {
     let answer = Header::new(OpCode::Close);
-    if data.len() < 2 {
-        return Ok((answer, None))
+    let code = match parse_close(data)? {
+        Some((code, _)) => code,
+        None => return Ok((answer, None))
+    };
+    if !is_valid_close_code(code) {
+        return Err(Error::InvalidCloseCode(code))
+    }
+    Ok((answer, Some(code)))
+}
+
+/// Is the given status code allowed to appear in a close message?
+///
+/// Codes in 3000 – 4999 are reserved for libraries, frameworks and
+/// applications and always accepted. Of the codes defined by RFC 6455,
+/// those which must not be sent (e.g. 1005, 1006 and 1015) are rejected,
+/// as is everything below 1000.
+pub fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000 ..= 1003 | 1007 ..= 1011 | 3000 ..= 4999)
+}
+
+/// Parse the payload of a close message into status code and reason.
+///
+/// An empty payload has no status code and gives `None`. A payload of a
+/// single byte is a protocol error and the reason must be valid UTF-8.
+/// The reason is borrowed from `data`.
+pub fn parse_close(data: &[u8]) -> Result<Option<(u16, &str)>, Error> {
+    match data.len() {
+        0 => Ok(None),
+        1 => Err(Error::Codec(base::Error::InvalidControlFrameLen)),
+        _ => {
+            let code = u16::from_be_bytes([data[0], data[1]]);
+            let reason = str::from_utf8(&data[2 ..])?;
+            Ok(Some((code, reason)))
+        }
     }
-    std::str::from_utf8(&data[2 ..])?; // check reason is properly encoded
-    let code = u16::from_be_bytes([data[0], data[1]]);
-    match code {
-        | 1000 ..= 1003
-        | 1007 ..= 1011
-        | 1015
-        | 3000 ..= 4999 => Ok((answer, Some(code))), // acceptable codes
-        _               => Ok((answer, Some(1002))) // invalid code => protocol error (1002)
+}
+
+/// Append the payload of a close message with status code and reason to `buf`.
+///
+/// The reason may be at most 123 bytes long, otherwise
+/// [`Error::ControlFrameTooLarge`] is returned.
+pub fn encode_close(code: u16, reason: &str, buf: &mut Vec<u8>) -> Result<(), Error> {
+    if reason.len() > 123 {
+        return Err(Error::ControlFrameTooLarge(2 + reason.len()))
     }
+    buf.extend_from_slice(&code.to_be_bytes());
+    buf.extend_from_slice(reason.as_bytes());
+    Ok(())
 }
 
 /// Errors which may occur when sending or receiving messages.
@@ -564,14 +1743,57 @@ pub enum Error {
     Extension(crate::BoxedError),
     /// An unexpected opcode was encountered.
     UnexpectedOpCode(OpCode),
-    /// A close reason was not correctly UTF-8 encoded.
+    /// The masking of a frame does not match the connection mode.
+    InvalidMasking,
+    /// A text message or close reason was not correctly UTF-8 encoded.
     Utf8(str::Utf8Error),
     /// The total message payload data size exceeds the configured maximum.
     MessageTooLarge { current: usize, maximum: usize },
+    /// Too many PINGs have not been answered by the remote.
+    UnansweredPings,
+    /// The remote sent messages faster than the configured rate limit allows.
+    RateLimitExceeded,
+    /// The remote sent a close message with a status code that is not allowed.
+    InvalidCloseCode(u16),
+    /// We were asked to send a control frame with more than 125 bytes of payload.
+    ControlFrameTooLarge(usize),
     /// The connection is closed.
     Closed
 }
 
+impl Error {
+    /// The status code a connection is closed with because of this error.
+    ///
+    /// Protocol violations of the remote map to 1002, invalid UTF-8 to
+    /// 1007, rate limiting to 1008, too large messages to 1009 and errors
+    /// of extensions or unanswered PINGs to 1011 (internal error). Extension
+    /// errors caused by the remote, i.e. [`PeerError`]s, map to 1002 or 1007.
+    /// I/O errors and closed connections do not permit sending a close frame
+    /// and have no status code, neither do errors of our own sending side.
+    pub fn close_code(&self) -> Option<u16> {
+        match self {
+            | Error::Io(_)
+            | Error::Codec(base::Error::Io(_))
+            | Error::ControlFrameTooLarge(_)
+            | Error::Closed => None,
+            Error::Codec(base::Error::PayloadTooLarge { .. }) => Some(1009),
+            | Error::Codec(_)
+            | Error::UnexpectedOpCode(_)
+            | Error::InvalidMasking
+            | Error::InvalidCloseCode(_) => Some(1002),
+            Error::Utf8(_) => Some(1007),
+            Error::RateLimitExceeded => Some(1008),
+            Error::MessageTooLarge { .. } => Some(1009),
+            Error::Extension(e) => match e.downcast_ref::<PeerError>() {
+                Some(PeerError::Protocol(_)) => Some(1002),
+                Some(PeerError::InvalidData(_)) => Some(1007),
+                None => Some(1011)
+            }
+            Error::UnansweredPings => Some(1011)
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -583,10 +1805,20 @@ impl fmt::Display for Error {
                 write!(f, "extension error: {}", e),
             Error::UnexpectedOpCode(c) =>
                 write!(f, "unexpected opcode: {}", c),
+            Error::InvalidMasking =>
+                f.write_str("invalid frame masking"),
             Error::Utf8(e) =>
                 write!(f, "utf-8 error: {}", e),
             Error::MessageTooLarge { current, maximum } =>
                 write!(f, "message too large: len >= {}, maximum = {}", current, maximum),
+            Error::UnansweredPings =>
+                f.write_str("pings have not been answered"),
+            Error::RateLimitExceeded =>
+                f.write_str("rate limit exceeded"),
+            Error::InvalidCloseCode(c) =>
+                write!(f, "invalid close code: {}", c),
+            Error::ControlFrameTooLarge(n) =>
+                write!(f, "control frame too large: len = {}, maximum = 125", n),
             Error::Closed =>
                 f.write_str("connection closed")
         }
@@ -601,7 +1833,12 @@ impl std::error::Error for Error {
             Error::Extension(e) => Some(&**e),
             Error::Utf8(e) => Some(e),
             Error::UnexpectedOpCode(_)
+            | Error::InvalidMasking
             | Error::MessageTooLarge {..}
+            | Error::UnansweredPings
+            | Error::RateLimitExceeded
+            | Error::InvalidCloseCode(_)
+            | Error::ControlFrameTooLarge(_)
             | Error::Closed
             => None
         }
@@ -629,3 +1866,1136 @@ impl From<base::Error> for Error {
         Error::Codec(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{base::{self, Header, OpCode}, data::{Data, Message, Outgoing}, extension::Extension};
+    use crate::{data::ByteSlice125, test_util::{Stub, capture_logs, duplex, read_frame}};
+    use std::convert::TryFrom;
+    use futures::{executor::block_on, io::BufWriter, prelude::*};
+    use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}, task::{Context, Poll}, time::{Duration, Instant}};
+    use super::{Bucket, Builder, Error, Mode, RateLimit, RateLimitAction, RateLimiter, Utf8Policy, Utf8Validator};
+    use super::{encode_close, is_valid_close_code, parse_close};
+
+    type Seen<T> = Arc<std::sync::Mutex<Vec<T>>>;
+
+    /// An extension whose frames start with two bytes of extension data.
+    ///
+    /// The extension data is recorded in `seen`, except for `[0, 0]`, which
+    /// the extension fails to decode.
+    fn ext_data(seen: Seen<Vec<u8>>) -> Box<dyn Extension + Send> {
+        let stub = Stub::new("ext-data")
+            .extension_data(2, move |_, data| {
+                if data == [0, 0] {
+                    return Err("no extension data".into())
+                }
+                seen.lock().unwrap().push(data.to_vec());
+                Ok(())
+            })
+            .on_decode(|header, data| {
+                assert_eq!(header.payload_len(), data.len());
+                Ok(())
+            });
+        Box::new(stub)
+    }
+
+    /// An extension reserving RSV2.
+    fn rsv2() -> Box<dyn Extension + Send> {
+        let stub = Stub::new("rsv2")
+            .reserved_bits((false, true, false))
+            .on_decode(|header, _| {
+                header.set_rsv2(false);
+                Ok(())
+            });
+        Box::new(stub)
+    }
+
+    #[test]
+    fn send_all_preserves_order() {
+        let (a, b) = duplex();
+        let (mut sender, _) = Builder::new(a, Mode::Client).finish();
+        let (_, mut receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            let texts = ["one", "two", "three", "four", "five"];
+            sender.send_all(texts.iter().map(|t| Outgoing::Text(t))).await.unwrap();
+            for t in &texts {
+                let mut message = Vec::new();
+                let data = receiver.receive_data(&mut message).await.unwrap();
+                assert!(data.is_text());
+                assert_eq!(t.as_bytes(), &message[..])
+            }
+        })
+    }
+
+    #[test]
+    fn reject_continuation_as_first_frame() {
+        // A final and a non-final continuation frame, each without a preceding data frame.
+        for frame in &[[0x80, 0x01, b'x'], [0x00, 0x01, b'x']] {
+            let (mut remote, local) = duplex();
+            let (_, mut receiver) = Builder::new(local, Mode::Client).finish();
+            block_on(async {
+                remote.write_all(frame).await.unwrap();
+                let mut message = Vec::new();
+                assert!(matches! {
+                    receiver.receive(&mut message).await,
+                    Err(Error::UnexpectedOpCode(OpCode::Continue))
+                })
+            })
+        }
+    }
+
+    #[test]
+    fn send_premasked() {
+        let (a, b) = duplex();
+        let (mut sender, _) = Builder::new(a, Mode::Client).finish();
+        let (mut server_sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            let mut header = Header::new(OpCode::Binary);
+            header.set_masked(true).set_mask(0x1234_5678);
+            let mut data = b"hello world".to_vec();
+            base::Codec::apply_mask(&header, &mut data);
+            sender.send_premasked(OpCode::Binary, 0x1234_5678, &data).await.unwrap();
+
+            let mut message = Vec::new();
+            let data = receiver.receive_data(&mut message).await.unwrap();
+            assert!(data.is_binary());
+            assert_eq!(b"hello world", &message[..]);
+
+            assert!(matches! {
+                server_sender.send_premasked(OpCode::Binary, 0, b"x").await,
+                Err(Error::InvalidMasking)
+            })
+        })
+    }
+
+    #[test]
+    fn receive_empty_text() {
+        let (mut remote, local) = duplex();
+        let (_, mut receiver) = Builder::new(local, Mode::Client).finish();
+        block_on(async {
+            // An empty text message, followed by a fragmented one with an empty first fragment.
+            remote.write_all(&[0x81, 0x00]).await.unwrap();
+            remote.write_all(&[0x01, 0x00, 0x80, 0x02, b'h', b'i']).await.unwrap();
+
+            let mut message = Vec::new();
+            let data = receiver.receive_data(&mut message).await.unwrap();
+            assert!(data.is_text());
+            assert!(data.is_empty());
+
+            let data = receiver.receive_data(&mut message).await.unwrap();
+            assert!(data.is_text());
+            assert_eq!(b"hi", &message[..])
+        })
+    }
+
+    #[test]
+    fn builder_options_take_effect() {
+        let (a, _b) = duplex();
+        let mut builder = Builder::new(a, Mode::Server);
+        builder
+            .set_buffer(bytes::BytesMut::from(&[0x81, 0x00][..]))
+            .set_max_message_size(1024)
+            .set_max_frame_size(512)
+            .add_extensions(Vec::new());
+        let (sender, receiver) = builder.finish();
+        assert_eq!(1024, receiver.max_message_size);
+        assert_eq!(512, receiver.codec.max_data_size());
+        assert_eq!(512, sender.codec.max_data_size());
+        assert_eq!(&[0x81, 0x00], &receiver.buffer[..]);
+        assert!(!receiver.has_extensions)
+    }
+
+    #[test]
+    fn split_extension_data() {
+        let seen = Seen::default();
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.add_extensions(Some(ext_data(seen.clone())));
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            remote.write_all(&[0x82, 0x04, 0xA, 0xB, 1, 2]).await.unwrap();
+            // Application data already in `message` must not be passed to extensions.
+            let mut message = b"xy".to_vec();
+            let data = receiver.receive_data(&mut message).await.unwrap();
+            assert_eq!(2, data.len());
+            assert_eq!(b"xy\x01\x02", &message[..]);
+
+            // Every fragment carries its own extension data.
+            remote.write_all(&[0x02, 0x03, 0xC, 0xD, 3, 0x80, 0x03, 0xE, 0xF, 4]).await.unwrap();
+            message.clear();
+            assert_eq!(2, receiver.receive_data(&mut message).await.unwrap().len());
+            assert_eq!(&[3, 4], &message[..]);
+
+            // Frames too short to hold the extension data are a protocol error.
+            remote.write_all(&[0x82, 0x01, 0xA]).await.unwrap();
+            assert!(matches!(receiver.receive_data(&mut message).await, Err(Error::Extension(_))))
+        });
+        let seen = seen.lock().unwrap().clone();
+        assert_eq!(vec![vec![0xA, 0xB], vec![0xC, 0xD], vec![0xE, 0xF]], seen);
+        let (header, payload) = block_on(read_frame(&mut remote));
+        assert_eq!(OpCode::Close, header.opcode());
+        assert_eq!(1002_u16.to_be_bytes(), &payload[..])
+    }
+
+    #[test]
+    fn extensions_get_only_new_fragment() {
+        let seen = Seen::default();
+        let record = {
+            let seen = seen.clone();
+            Stub::new("record").on_decode(move |header, data| {
+                seen.lock().unwrap().push((header.is_fin(), data.len()));
+                Ok(())
+            })
+        };
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.add_extensions(std::iter::once(Box::new(record) as Box<_>));
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            remote.write_all(&[0x02, 0x03, 1, 2, 3, 0x00, 0x04, 4, 5, 6, 7, 0x80, 0x04, 8, 9, 10, 11]).await.unwrap();
+            let mut message = b"xy".to_vec();
+            assert_eq!(11, receiver.receive_data(&mut message).await.unwrap().len());
+            assert_eq!(&b"xy\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b"[..], &message[..])
+        });
+        let seen = seen.lock().unwrap().clone();
+        assert_eq!(vec![(false, 3), (false, 4), (true, 4)], seen)
+    }
+
+    #[test]
+    fn fail_on_unanswered_pings() {
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.set_max_outstanding_pings(2);
+        let (mut sender, mut receiver) = builder.finish();
+        block_on(async {
+            let ping = |data: &'static [u8]| ByteSlice125::try_from(data).unwrap();
+            sender.send_ping(ping(b"1")).await.unwrap();
+            sender.send_ping(ping(b"2")).await.unwrap();
+
+            // PONGs not echoing the last PING, e.g. unsolicited ones, do not count.
+            let mut message = Vec::new();
+            for pong in &[&[0x8A, 0x00][..], &[0x8A, 0x01, b'1'], &[0x8A, 0x02, b'2', b'2']] {
+                remote.write_all(pong).await.unwrap();
+                assert!(receiver.receive(&mut message).await.unwrap().is_pong());
+                assert_eq!(2, sender.pings.outstanding())
+            }
+
+            // A PONG answering the last PING acknowledges all outstanding PINGs.
+            remote.write_all(&[0x8A, 0x01, b'2']).await.unwrap();
+            assert!(receiver.receive(&mut message).await.unwrap().is_pong());
+            assert_eq!(0, sender.pings.outstanding());
+
+            sender.send_ping(ping(b"3")).await.unwrap();
+            sender.send_ping(ping(b"4")).await.unwrap();
+            assert!(matches!(sender.send_ping(ping(b"5")).await, Err(Error::UnansweredPings)));
+
+            for _ in 0 .. 4 {
+                assert_eq!(OpCode::Ping, read_frame(&mut remote).await.0.opcode())
+            }
+            let (header, payload) = read_frame(&mut remote).await;
+            assert_eq!(OpCode::Close, header.opcode());
+            assert_eq!(1011_u16.to_be_bytes(), &payload[..])
+        })
+    }
+
+    #[test]
+    fn rate_limit_refill() {
+        let start = Instant::now();
+        let mut limit = RateLimit::new();
+        limit.set_messages_per_second(2).set_bytes_per_second(100);
+        let mut limiter = RateLimiter::new(&limit, start);
+        assert!(limiter.admit(10, start));
+        assert!(limiter.admit(10, start));
+        assert!(!limiter.admit(10, start));
+
+        // Half a second refills one message.
+        let t = start + Duration::from_millis(500);
+        assert!(limiter.admit(10, t));
+        assert!(!limiter.admit(10, t));
+
+        // A long pause refills the bucket to its capacity only.
+        let t = t + Duration::from_secs(60);
+        assert!(limiter.admit(10, t));
+        assert!(limiter.admit(10, t));
+        assert!(!limiter.admit(10, t));
+
+        // A message larger than the byte limit passes once and leaves the bucket in debt.
+        let mut bucket = Bucket::new(100.0, start);
+        assert!(bucket.take(250.0, start));
+        assert!(!bucket.take(1.0, start + Duration::from_secs(1)));
+        assert!(bucket.take(1.0, start + Duration::from_millis(2510)))
+    }
+
+    #[test]
+    fn close_on_rate_limit() {
+        let (mut remote, local) = duplex();
+        let mut limit = RateLimit::new();
+        limit.set_messages_per_second(2);
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.set_rate_limit(limit);
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            for _ in 0 .. 3 {
+                remote.write_all(&[0x81, 0x01, b'a']).await.unwrap()
+            }
+            let mut message = Vec::new();
+            assert!(receiver.receive_data(&mut message).await.is_ok());
+            assert!(receiver.receive_data(&mut message).await.is_ok());
+            assert!(matches!(receiver.receive_data(&mut message).await, Err(Error::RateLimitExceeded)));
+            let (header, payload) = read_frame(&mut remote).await;
+            assert_eq!(OpCode::Close, header.opcode());
+            assert_eq!(1008_u16.to_be_bytes(), &payload[..])
+        })
+    }
+
+    #[test]
+    fn drop_on_rate_limit() {
+        let (mut remote, local) = duplex();
+        let mut limit = RateLimit::new();
+        limit.set_bytes_per_second(4).set_action(RateLimitAction::Drop);
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.set_rate_limit(limit);
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            remote.write_all(&[0x82, 0x05, 1, 2, 3, 4, 5]).await.unwrap();
+            remote.write_all(&[0x82, 0x01, 6]).await.unwrap();
+            remote.close().await.unwrap();
+            let mut message = Vec::new();
+            assert!(receiver.receive_data(&mut message).await.is_ok());
+            assert!(matches!(receiver.receive_data(&mut message).await, Err(Error::Closed)));
+            assert_eq!(&[1, 2, 3, 4, 5], &message[..])
+        })
+    }
+
+    #[test]
+    fn reject_masked_frame_in_client_mode() {
+        let (mut remote, local) = duplex();
+        let (_, mut receiver) = Builder::new(local, Mode::Client).finish();
+        block_on(async {
+            // A masked frame, truncated within the mask key.
+            remote.write_all(&[0x82, 0x81, 0x37, 0xfa]).await.unwrap();
+            let mut message = Vec::new();
+            let future = receiver.receive(&mut message);
+            futures::pin_mut!(future);
+            assert!(futures::poll!(&mut future).is_pending());
+            remote.write_all(&[0x21, 0x3d, 0x00]).await.unwrap();
+            assert!(matches!(future.await, Err(Error::InvalidMasking)))
+        })
+    }
+
+    #[test]
+    fn reject_unmasked_frame_in_server_mode() {
+        let (mut remote, local) = duplex();
+        let (_, mut receiver) = Builder::new(local, Mode::Server).finish();
+        block_on(async {
+            remote.write_all(&[0x82, 0x01, 0x00]).await.unwrap();
+            let mut message = Vec::new();
+            assert!(matches!(receiver.receive(&mut message).await, Err(Error::InvalidMasking)))
+        })
+    }
+
+    #[test]
+    fn receive_by_reference() {
+        let (mut remote, local) = duplex();
+        let (_, mut receiver) = Builder::new(local, Mode::Client).finish();
+        block_on(async {
+            remote.write_all(&[0x82, 0x03, 1, 2, 3, 0x81, 0x02, b'h', b'i']).await.unwrap();
+            let (data, payload) = receiver.receive_data_ref().await.unwrap();
+            assert!(data.is_binary());
+            assert_eq!(&[1, 2, 3], payload);
+            let address = payload.as_ptr();
+            let (data, payload) = receiver.receive_data_ref().await.unwrap();
+            assert!(data.is_text());
+            assert_eq!(b"hi", payload);
+            assert_eq!(address, payload.as_ptr()) // buffer has been reused
+        })
+    }
+
+    #[test]
+    fn utf8_policies() {
+        const INVALID: [u8; 5] = [0x81, 0x03, b'a', 0xFF, b'b'];
+
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.set_utf8_policy(Utf8Policy::Lossy);
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            remote.write_all(&INVALID).await.unwrap();
+            let mut message = Vec::new();
+            let data = receiver.receive_data(&mut message).await.unwrap();
+            assert_eq!("a\u{FFFD}b", std::str::from_utf8(&message).unwrap());
+            assert_eq!(message.len(), data.len())
+        });
+
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.set_utf8_policy(Utf8Policy::Skip);
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            remote.write_all(&INVALID).await.unwrap();
+            remote.write_all(&[0x81, 0x01, b'c']).await.unwrap();
+            let mut message = Vec::new();
+            receiver.receive_data(&mut message).await.unwrap();
+            assert_eq!(b"c", &message[..])
+        });
+
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.set_utf8_policy(Utf8Policy::Strict);
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            remote.write_all(&INVALID).await.unwrap();
+            let mut message = Vec::new();
+            assert!(matches!(receiver.receive_data(&mut message).await, Err(Error::Utf8(_))));
+            let (header, payload) = read_frame(&mut remote).await;
+            assert_eq!(OpCode::Close, header.opcode());
+            assert_eq!(1007_u16.to_be_bytes(), &payload[..])
+        })
+    }
+
+    #[test]
+    fn auto_flush() {
+        let (mut remote, local) = duplex();
+        let (mut sender, _receiver) = Builder::new(BufWriter::new(local), Mode::Server).finish();
+        block_on(async {
+            sender.send_text("hi").await.unwrap();
+            let (header, payload) = read_frame(&mut remote).await;
+            assert_eq!(OpCode::Text, header.opcode());
+            assert_eq!(b"hi", &payload[..])
+        });
+
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(BufWriter::new(local), Mode::Server);
+        builder.set_auto_flush(false);
+        let (mut sender, _receiver) = builder.finish();
+        block_on(async {
+            sender.send_text("hi").await.unwrap();
+            let mut byte = [0];
+            assert!(futures::poll!(remote.read(&mut byte)).is_pending());
+            sender.send_ping(ByteSlice125::try_from(&b"x"[..]).unwrap()).await.unwrap();
+            let (header, _) = read_frame(&mut remote).await;
+            assert_eq!(OpCode::Text, header.opcode());
+            let (header, _) = read_frame(&mut remote).await;
+            assert_eq!(OpCode::Ping, header.opcode())
+        })
+    }
+
+    #[test]
+    fn reserved_bits_require_extension() {
+        async fn receive(frame: &[u8], with_extension: bool) -> Result<Data, Error> {
+            let (mut remote, local) = duplex();
+            let mut builder = Builder::new(local, Mode::Client);
+            if with_extension {
+                builder.add_extensions(std::iter::once(rsv2()));
+            }
+            let (_, mut receiver) = builder.finish();
+            remote.write_all(frame).await.unwrap();
+            let mut message = Vec::new();
+            receiver.receive_data(&mut message).await
+        }
+        block_on(async {
+            let rsv1 = [0xC2, 0x01, 0x00];
+            let rsv2 = [0xA2, 0x01, 0x00];
+            let rsv3 = [0x92, 0x01, 0x00];
+            assert!(receive(&rsv2, true).await.is_ok());
+            assert!(matches! {
+                receive(&rsv2, false).await,
+                Err(Error::Codec(base::Error::InvalidReservedBit(2)))
+            });
+            assert!(matches! {
+                receive(&rsv1, false).await,
+                Err(Error::Codec(base::Error::InvalidReservedBit(1)))
+            });
+            assert!(matches! {
+                receive(&rsv1, true).await,
+                Err(Error::Codec(base::Error::InvalidReservedBit(1)))
+            });
+            assert!(matches! {
+                receive(&rsv3, true).await,
+                Err(Error::Codec(base::Error::InvalidReservedBit(3)))
+            })
+        })
+    }
+
+    #[test]
+    fn read_payload_in_place() {
+        const LEN: usize = 1024 * 1024;
+        let (mut remote, local) = duplex();
+        let (_, mut receiver) = Builder::new(local, Mode::Client).finish();
+        let payload: Vec<u8> = (0 .. LEN).map(|i| i as u8).collect();
+        let mut message = Vec::with_capacity(LEN);
+        let address = message.as_ptr();
+        block_on(async {
+            let mut header = Header::new(OpCode::Binary);
+            header.set_payload_len(LEN);
+            remote.write_all(base::Codec::new().encode_header(&header)).await.unwrap();
+            let future = receiver.receive_data(&mut message);
+            futures::pin_mut!(future);
+            for chunk in payload.chunks(4096) {
+                assert!(futures::poll!(&mut future).is_pending());
+                remote.write_all(chunk).await.unwrap()
+            }
+            assert!(future.await.unwrap().is_binary())
+        });
+        // The payload has been read directly into the message buffer.
+        assert_eq!(address, message.as_ptr());
+        assert!(payload == message)
+    }
+
+    #[test]
+    fn close_codes() {
+        async fn close_code(frame: &[u8]) -> Option<u16> {
+            let (mut remote, local) = duplex();
+            let mut builder = Builder::new(local, Mode::Client);
+            builder.add_extensions(std::iter::once(ext_data(Seen::default()))).set_max_message_size(8);
+            let (_, mut receiver) = builder.finish();
+            remote.write_all(frame).await.unwrap();
+            let mut message = Vec::new();
+            let e = receiver.receive(&mut message).await.unwrap_err();
+            let (header, payload) = read_frame(&mut remote).await;
+            assert_eq!(OpCode::Close, header.opcode());
+            assert_eq!(e.close_code(), Some(u16::from_be_bytes([payload[0], payload[1]])));
+            e.close_code()
+        }
+        block_on(async {
+            // Reserved data and control opcodes.
+            assert_eq!(Some(1002), close_code(&[0x83, 0x00]).await);
+            assert_eq!(Some(1002), close_code(&[0x8B, 0x00]).await);
+            // Unexpected continuation frame.
+            assert_eq!(Some(1002), close_code(&[0x80, 0x00]).await);
+            // New data frame while a fragmented message is in progress.
+            assert_eq!(Some(1002), close_code(&[0x02, 0x00, 0x82, 0x00]).await);
+            assert_eq!(Some(1002), close_code(&[0x02, 0x00, 0x01, 0x00]).await);
+            // PING with 126 bytes of payload.
+            assert_eq!(Some(1002), close_code(&[0x89, 0x7E, 0x00, 0x7E]).await);
+            // Fragmented PING.
+            assert_eq!(Some(1002), close_code(&[0x09, 0x00]).await);
+            // Close frame with a single byte of payload.
+            assert_eq!(Some(1002), close_code(&[0x88, 0x01, 0x03]).await);
+            // Close frames with codes which must not be sent.
+            assert_eq!(Some(1002), close_code(&[0x88, 0x02, 0x03, 0xe7]).await);
+            assert_eq!(Some(1002), close_code(&[0x88, 0x02, 0x03, 0xed]).await);
+            assert_eq!(Some(1002), close_code(&[0x88, 0x02, 0x03, 0xf7]).await);
+            // Close frame with invalid UTF-8 reason.
+            assert_eq!(Some(1007), close_code(&[0x88, 0x03, 0x03, 0xe8, 0xFF]).await);
+            // Message exceeding the maximum size.
+            assert_eq!(Some(1009), close_code(&[0x82, 0x09, 0, 0, 0, 0, 0, 0, 0, 0, 0]).await);
+            // Frame too short for the extension data.
+            assert_eq!(Some(1002), close_code(&[0x82, 0x01, 0x00]).await);
+            // Extension failing to decode.
+            assert_eq!(Some(1011), close_code(&[0x82, 0x02, 0x00, 0x00]).await)
+        });
+
+        #[cfg(feature = "deflate")]
+        block_on(async {
+            async fn deflate_close_code(frame: &[u8]) -> Option<u16> {
+                use crate::extension::deflate::Deflate;
+                let mut deflate = Deflate::new(Mode::Client);
+                let mut server = Deflate::new(Mode::Server);
+                server.configure(deflate.params()).unwrap();
+                deflate.configure(server.params()).unwrap();
+                let (mut remote, local) = duplex();
+                let mut builder = Builder::new(local, Mode::Client);
+                builder.add_extensions(std::iter::once(Box::new(deflate) as Box<_>));
+                let (_, mut receiver) = builder.finish();
+                remote.write_all(frame).await.unwrap();
+                let e = receiver.receive(&mut Vec::new()).await.unwrap_err();
+                let (header, payload) = read_frame(&mut remote).await;
+                assert_eq!(OpCode::Close, header.opcode());
+                assert_eq!(e.close_code(), Some(u16::from_be_bytes([payload[0], payload[1]])));
+                e.close_code()
+            }
+            // Continuation frames with RSV1 set.
+            assert_eq!(Some(1002), deflate_close_code(&[0x41, 0x00, 0xC0, 0x00]).await);
+            assert_eq!(Some(1002), deflate_close_code(&[0x41, 0x01, 0x00, 0x40, 0x01, 0x00]).await);
+            // Corrupt compressed data (reserved block type).
+            assert_eq!(Some(1007), deflate_close_code(&[0xC1, 0x01, 0x07]).await)
+        })
+    }
+
+    #[test]
+    fn reassembled_message_keeps_first_opcode() {
+        let (mut remote, local) = duplex();
+        let (_, mut receiver) = Builder::new(local, Mode::Client).finish();
+        block_on(async {
+            // Binary message in three fragments with an interleaved PING.
+            remote.write_all(&[0x02, 0x01, 1, 0x89, 0x00, 0x00, 0x01, 2, 0x80, 0x01, 3]).await.unwrap();
+            remote.write_all(&[0x01, 0x01, b'a', 0x80, 0x01, b'b']).await.unwrap();
+            let mut message = Vec::new();
+            assert!(matches!(receiver.receive_data(&mut message).await, Ok(Data::Binary(3))));
+            assert_eq!(&[1, 2, 3], &message[..]);
+            message.clear();
+            assert!(matches!(receiver.receive_data(&mut message).await, Ok(Data::Text(2))));
+            assert_eq!(b"ab", &message[..])
+        })
+    }
+
+    #[test]
+    fn log_continuation_origin() {
+        let (mut remote, local) = duplex();
+        let (_, mut receiver) = Builder::new(local, Mode::Client).finish();
+        let logs = capture_logs(|| block_on(async {
+            remote.write_all(&[0x01, 0x01, b'a', 0x00, 0x01, b'b', 0x80, 0x01, b'c']).await.unwrap();
+            let mut message = Vec::new();
+            assert!(matches!(receiver.receive_data(&mut message).await, Ok(Data::Text(3))))
+        }));
+        let continuations: Vec<_> = logs.iter().filter(|m| m.contains("recv: (Continue ")).collect();
+        assert_eq!(2, continuations.len(), "{:?}", logs);
+        assert!(continuations.iter().all(|m| m.ends_with(" (of Text)")), "{:?}", continuations)
+    }
+
+    #[test]
+    fn validate_unmasked_close_payload() {
+        let (mut remote, local) = duplex();
+        let (_, mut receiver) = Builder::new(local, Mode::Server).finish();
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![0x88, 0x85];
+        frame.extend_from_slice(&mask);
+        frame.extend(b"\x03\xe8bye".iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        block_on(async {
+            remote.write_all(&frame).await.unwrap();
+            let mut message = Vec::new();
+            assert!(matches!(receiver.receive(&mut message).await, Err(Error::Closed)));
+            let (header, payload) = read_frame(&mut remote).await;
+            assert_eq!(OpCode::Close, header.opcode());
+            assert_eq!(1000_u16.to_be_bytes(), &payload[..])
+        })
+    }
+
+    #[test]
+    fn reassemble_many_fragments() {
+        let (mut remote, local) = duplex();
+        let (_, mut receiver) = Builder::new(local, Mode::Client).finish();
+        let mut frames = Vec::new();
+        for i in 0 .. 100_u8 {
+            let first = if i == 0 { 0x02 } else if i == 99 { 0x80 } else { 0x00 };
+            frames.extend_from_slice(&[first, 0x02, i, i])
+        }
+        let mut message = Vec::with_capacity(200);
+        let address = message.as_ptr();
+        block_on(async {
+            remote.write_all(&frames).await.unwrap();
+            assert!(matches!(receiver.receive_data(&mut message).await, Ok(Data::Binary(200))))
+        });
+        assert_eq!(address, message.as_ptr());
+        assert!(message.chunks(2).enumerate().all(|(i, c)| c == [i as u8, i as u8]))
+    }
+
+    #[test]
+    fn send_from_reader() {
+        const LEN: usize = 256 * 1024 + 3;
+        let (a, b) = duplex();
+        let (mut sender, _) = Builder::new(a, Mode::Client).finish();
+        let (_, mut receiver) = Builder::new(b, Mode::Server).finish();
+        let payload: Vec<u8> = (0 .. LEN).map(|i| (i % 251) as u8).collect();
+        block_on(async {
+            let reader = futures::io::Cursor::new(&payload);
+            sender.send_from_reader(OpCode::Binary, LEN, reader).await.unwrap();
+            let mut message = Vec::new();
+            assert!(matches!(receiver.receive_data(&mut message).await, Ok(Data::Binary(LEN))));
+            assert!(payload == message)
+        })
+    }
+
+    #[test]
+    fn send_from_failing_reader() {
+        struct Failing;
+
+        impl AsyncRead for Failing {
+            fn poll_read(self: std::pin::Pin<&mut Self>, _: &mut Context, _: &mut [u8]) -> Poll<std::io::Result<usize>> {
+                Poll::Ready(Err(std::io::ErrorKind::Other.into()))
+            }
+        }
+
+        let (mut remote, local) = duplex();
+        let (mut sender, receiver) = Builder::new(local, Mode::Server).finish();
+        block_on(async {
+            // The header and the first chunk have been sent when the reader fails.
+            let reader = futures::io::Cursor::new(vec![1; 8 * 1024]).chain(Failing);
+            assert!(matches!(sender.send_from_reader(OpCode::Binary, 32 * 1024, reader).await, Err(Error::Io(_))));
+            assert!(sender.is_closed() && receiver.is_closed());
+            assert!(matches!(sender.send_text("next").await, Err(Error::Closed)));
+
+            let mut sent = Vec::new();
+            remote.read_to_end(&mut sent).await.unwrap();
+            assert_eq!(&[0x82, 0x7E, 0x80, 0x00], &sent[.. 4]);
+            assert_eq!(4 + 8 * 1024, sent.len())
+        })
+    }
+
+    #[test]
+    fn close_reason_length() {
+        let (mut remote, local) = duplex();
+        let (mut sender, _) = Builder::new(local, Mode::Client).finish();
+        block_on(async {
+            let reason = "x".repeat(200);
+            assert!(matches! {
+                sender.close_with_reason(1001, &reason).await,
+                Err(Error::ControlFrameTooLarge(202))
+            });
+            assert!(!sender.is_closing());
+            sender.close_with_reason(1001, &reason[.. 123]).await.unwrap();
+            let (header, payload) = read_frame(&mut remote).await;
+            assert_eq!(OpCode::Close, header.opcode());
+            assert_eq!(125, payload.len());
+            assert_eq!(1001_u16.to_be_bytes(), &payload[.. 2])
+        });
+
+        // A close frame with a 124 byte reason is rejected by the remote.
+        let (mut remote, local) = duplex();
+        let (_, mut receiver) = Builder::new(local, Mode::Client).finish();
+        block_on(async {
+            let mut frame = vec![0x88, 0x7E, 0x00, 126, 0x03, 0xe8];
+            frame.extend_from_slice(&[b'x'; 124]);
+            remote.write_all(&frame).await.unwrap();
+            let mut message = Vec::new();
+            assert!(matches! {
+                receiver.receive(&mut message).await,
+                Err(Error::Codec(base::Error::InvalidControlFrameLen))
+            })
+        })
+    }
+
+    #[test]
+    fn abort() {
+        let (a, mut b) = crate::test_util::duplex();
+        let (mut sender, mut receiver) = Builder::new(a, Mode::Server).finish();
+        block_on(async {
+            sender.abort().await.unwrap();
+            assert!(sender.is_closed() && receiver.is_closed());
+            let mut bytes = Vec::new();
+            b.read_to_end(&mut bytes).await.unwrap();
+            assert!(bytes.is_empty(), "no close frame must be written");
+            assert!(matches!(sender.send_text("hi").await, Err(Error::Closed)));
+            assert!(matches!(receiver.receive(&mut Vec::new()).await, Err(Error::Closed)))
+        })
+    }
+
+    #[test]
+    fn close_status() {
+        assert!(matches!(parse_close(&[]), Ok(None)));
+        assert!(matches!(parse_close(&[0x03]), Err(Error::Codec(base::Error::InvalidControlFrameLen))));
+        assert!(matches!(parse_close(&[0x03, 0xe8, 0xFF]), Err(Error::Utf8(_))));
+
+        let mut data = Vec::new();
+        encode_close(4000, "going away", &mut data).unwrap();
+        assert!(matches!(parse_close(&data), Ok(Some((4000, "going away")))));
+        assert!(matches!(encode_close(1000, &"x".repeat(124), &mut Vec::new()), Err(Error::ControlFrameTooLarge(126))));
+
+        let (mut remote, local) = duplex();
+        let (_, mut receiver) = Builder::new(local, Mode::Client).finish();
+        block_on(async {
+            assert!(receiver.close_status().is_none());
+            remote.write_all(&[0x88, 0x05, 0x03, 0xe8, b'b', b'y', b'e']).await.unwrap();
+            let mut message = Vec::new();
+            assert!(matches!(receiver.receive(&mut message).await, Err(Error::Closed)));
+            assert_eq!(Some((1000, "bye")), receiver.close_status())
+        })
+    }
+
+    #[test]
+    fn close_handshake_state() {
+        let (mut remote, local) = duplex();
+        let (mut sender, mut receiver) = Builder::new(local, Mode::Client).finish();
+        block_on(async {
+            assert!(!sender.is_closing() && !receiver.is_closing());
+            sender.close().await.unwrap();
+            assert!(sender.is_closing() && receiver.is_closing());
+            assert!(!sender.is_closed() && !receiver.is_closed());
+            assert!(matches!(sender.send_text("hi").await, Err(Error::Closed)));
+
+            // Data sent before the remote saw our close message is discarded.
+            let mut message = Vec::new();
+            remote.write_all(&[0x81, 0x02, b'h', b'i', 0x02, 0x01, b'a', 0x80, 0x01, b'b']).await.unwrap();
+            remote.write_all(&[0x88, 0x02, 0x03, 0xe8]).await.unwrap();
+            assert!(matches!(receiver.receive(&mut message).await, Err(Error::Closed)));
+            assert!(message.is_empty());
+            assert_eq!(Some((1000, "")), receiver.close_status());
+            assert!(sender.is_closed() && receiver.is_closed());
+
+            // Nothing is read after the remote's close message.
+            remote.write_all(&[0x81, 0x02, b'h', b'i']).await.unwrap();
+            assert!(matches!(receiver.receive(&mut message).await, Err(Error::Closed)));
+
+            // Only our close message has been sent.
+            let mut frames = Vec::new();
+            remote.read_to_end(&mut frames).await.unwrap();
+            assert_eq!(&[0x88, 0x82], &frames[.. 2]);
+            assert_eq!(8, frames.len())
+        });
+
+        // The same applies when receiving in chunks.
+        let (mut remote, local) = duplex();
+        let (mut sender, mut receiver) = Builder::new(local, Mode::Client).finish();
+        block_on(async {
+            sender.close().await.unwrap();
+            remote.write_all(&[0x82, 0x03, 1, 2, 3, 0x88, 0x00]).await.unwrap();
+            let mut chunk = Vec::new();
+            assert!(matches!(receiver.receive_chunk(&mut chunk, 2).await, Err(Error::Closed)));
+            assert!(chunk.is_empty() && receiver.is_closed())
+        })
+    }
+
+    #[test]
+    fn close_deadline() {
+        let timeout = std::time::Duration::from_secs(5);
+        let (mut remote, local) = duplex();
+        let (mut sender, mut receiver) = Builder::new(local, Mode::Client).finish();
+        block_on(async {
+            assert!(sender.close_sent_at().is_none());
+            assert!(!sender.close_deadline_passed(timeout, std::time::Instant::now()));
+            sender.close().await.unwrap();
+
+            let sent_at = sender.close_sent_at().unwrap();
+            assert_eq!(Some(sent_at), receiver.close_sent_at());
+            assert!(!receiver.close_deadline_passed(timeout, sent_at));
+            assert!(receiver.close_deadline_passed(timeout, sent_at + timeout));
+
+            remote.write_all(&[0x88, 0x00]).await.unwrap();
+            assert!(matches!(receiver.receive(&mut Vec::new()).await, Err(Error::Closed)));
+            assert!(!sender.close_deadline_passed(timeout, sent_at + timeout))
+        })
+    }
+
+    #[test]
+    fn reject_masked_frame_in_server_mode_on_send() {
+        let masking = Stub::new("masking").on_encode(|header, _| {
+            header.set_masked(true);
+            Ok(())
+        });
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Server);
+        builder.add_extensions(std::iter::once(Box::new(masking) as Box<_>));
+        let (mut sender, _) = builder.finish();
+        block_on(async {
+            assert!(matches!(sender.send_text("hi").await, Err(Error::InvalidMasking)));
+            sender.send_pong(ByteSlice125::try_from(&b"x"[..]).unwrap()).await.unwrap();
+            sender.flush().await.unwrap();
+            let (header, payload) = read_frame(&mut remote).await;
+            assert_eq!(OpCode::Pong, header.opcode());
+            assert!(!header.is_masked());
+            assert_eq!(b"x", &payload[..])
+        })
+    }
+
+    #[test]
+    fn receive_chunks() {
+        fn frame(first: u8, key: [u8; 4], data: &[u8]) -> Vec<u8> {
+            let mut frame = vec![first, 0x80 | data.len() as u8];
+            frame.extend_from_slice(&key);
+            frame.extend(data.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+            frame
+        }
+
+        let (mut remote, local) = duplex();
+        let (_, mut receiver) = Builder::new(local, Mode::Server).finish();
+        block_on(async {
+            remote.write_all(&frame(0x02, [1, 2, 3, 4], b"hello")).await.unwrap();
+            remote.write_all(&frame(0x89, [5, 6, 7, 8], b"ping")).await.unwrap();
+            remote.write_all(&frame(0x80, [9, 10, 11, 12], b" world")).await.unwrap();
+            remote.write_all(&frame(0x81, [13, 14, 15, 16], b"")).await.unwrap();
+
+            let mut message = Vec::new();
+            let mut chunks = Vec::new();
+            loop {
+                let chunk = receiver.receive_chunk(&mut message, 3).await.unwrap();
+                chunks.push(chunk.clone());
+                if chunk.is_last() {
+                    break
+                }
+            }
+            assert_eq!(b"hello world", &message[..]);
+            let lengths: Vec<_> = chunks.iter().map(|c| c.data().clone()).collect();
+            assert_eq!(vec![Data::Binary(3), Data::Binary(2), Data::Binary(3), Data::Binary(3)], lengths);
+
+            let chunk = receiver.receive_chunk(&mut message, 3).await.unwrap();
+            assert_eq!((&Data::Text(0), true), (chunk.data(), chunk.is_last()));
+
+            assert!(matches! {
+                receiver.receive_chunk(&mut message, 0).await,
+                Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput
+            });
+
+            let (header, payload) = read_frame(&mut remote).await;
+            assert_eq!(OpCode::Pong, header.opcode());
+            assert_eq!(b"ping", &payload[..])
+        });
+
+        let (_, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Server);
+        builder.add_extensions(std::iter::once(Box::new(Stub::new("stub")) as Box<_>));
+        let (_, mut receiver) = builder.finish();
+        assert!(matches!(block_on(receiver.receive_chunk(&mut Vec::new(), 1)), Err(Error::Extension(_))));
+
+        // Only the strict UTF-8 policy can be applied to chunks.
+        let (_, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Server);
+        builder.set_utf8_policy(Utf8Policy::Lossy);
+        let (_, mut receiver) = builder.finish();
+        assert!(matches! {
+            block_on(receiver.receive_chunk(&mut Vec::new(), 1)),
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput
+        })
+    }
+
+    #[test]
+    fn validate_utf8_chunks() {
+        fn validate(chunks: &[&[u8]]) -> Result<(), std::str::Utf8Error> {
+            let mut v = Utf8Validator::default();
+            for (i, c) in chunks.iter().enumerate() {
+                v.feed(c, i + 1 == chunks.len())?
+            }
+            Ok(())
+        }
+        let text = "a€b𝄞".as_bytes();
+        for i in 0 ..= text.len() {
+            for j in i ..= text.len() {
+                assert!(validate(&[&text[.. i], &text[i .. j], &text[j ..]]).is_ok(), "{} {}", i, j)
+            }
+        }
+        assert!(validate(&[b"a\xE2\x82", b"\xAC", b""]).is_ok());
+        assert!(validate(&[b"a\xE2", b"\x82"]).is_err());
+        assert!(validate(&[b"a\xE2", b"\x28\xA1"]).is_err());
+        assert!(validate(&[b"\xFF"]).is_err())
+    }
+
+    #[test]
+    fn receive_chunks_applies_utf8_policy_and_rate_limit() {
+        async fn receive(receiver: &mut super::Receiver<crate::test_util::Endpoint>) -> Result<Vec<u8>, Error> {
+            let mut message = Vec::new();
+            while !receiver.receive_chunk(&mut message, 1).await?.is_last() {}
+            Ok(message)
+        }
+
+        // Text split into chunks in the middle of a code point is valid.
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.strict();
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            remote.write_all(&[0x01, 0x02, b'a', 0xE2, 0x80, 0x02, 0x82, 0xAC]).await.unwrap();
+            assert_eq!("a€".as_bytes(), &receive(&mut receiver).await.unwrap()[..]);
+            remote.write_all(&[0x81, 0x02, b'a', 0xE2]).await.unwrap();
+            assert!(matches!(receive(&mut receiver).await, Err(Error::Utf8(_))));
+            let (header, payload) = read_frame(&mut remote).await;
+            assert_eq!(OpCode::Close, header.opcode());
+            assert_eq!(1007_u16.to_be_bytes(), &payload[..])
+        });
+
+        // The rate limit applies to every message, however it is fragmented.
+        let mut limit = RateLimit::new();
+        limit.set_messages_per_second(1);
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.set_rate_limit(limit.clone());
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            remote.write_all(&[0x02, 0x01, 1, 0x80, 0x01, 2, 0x82, 0x01, 3]).await.unwrap();
+            assert_eq!(&[1, 2], &receive(&mut receiver).await.unwrap()[..]);
+            assert!(matches!(receive(&mut receiver).await, Err(Error::RateLimitExceeded)))
+        });
+
+        // Dropped messages are skipped.
+        limit.set_action(RateLimitAction::Drop);
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.set_rate_limit(limit);
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            remote.write_all(&[0x82, 0x01, 1, 0x02, 0x01, 2, 0x80, 0x01, 3, 0x88, 0x00]).await.unwrap();
+            assert_eq!(&[1], &receive(&mut receiver).await.unwrap()[..]);
+            let mut chunk = Vec::new();
+            assert!(matches!(receiver.receive_chunk(&mut chunk, 1).await, Err(Error::Closed)));
+            assert!(chunk.is_empty())
+        })
+    }
+
+    #[test]
+    fn valid_close_codes() {
+        for code in &[0, 999, 1004, 1005, 1006, 1012, 1015, 2999, 5000] {
+            assert!(!is_valid_close_code(*code), "{}", code)
+        }
+        for code in &[1000, 1001, 1002, 1003, 1007, 1011, 3000, 3999, 4000, 4999] {
+            assert!(is_valid_close_code(*code), "{}", code)
+        }
+    }
+
+    #[test]
+    fn partial_frame_takes_no_extension_lock() {
+        struct Wakes(AtomicUsize);
+
+        impl futures::task::ArcWake for Wakes {
+            fn wake_by_ref(this: &Arc<Self>) {
+                this.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.add_extensions(std::iter::once(rsv2()));
+        let (sender, mut receiver) = builder.finish();
+
+        let wakes = Arc::new(Wakes(AtomicUsize::new(0)));
+        let waker = futures::task::waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        block_on(remote.write_all(&[0x82, 0x03, 1, 2])).unwrap();
+        let guard = block_on(sender.extensions.lock());
+        let mut message = Vec::new();
+        let future = receiver.receive_data(&mut message);
+        futures::pin_mut!(future);
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        drop(guard); // Would wake the receiver if it waited for the lock.
+        assert_eq!(0, wakes.0.load(Ordering::SeqCst));
+
+        block_on(remote.write_all(&[3])).unwrap();
+        assert!(matches!(future.as_mut().poll(&mut cx), Poll::Ready(Ok(Data::Binary(3)))))
+    }
+
+    #[test]
+    fn empty_messages() {
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.set_utf8_policy(Utf8Policy::Strict);
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            // Empty text, empty binary and an empty fragmented text message.
+            remote.write_all(&[0x81, 0x00, 0x82, 0x00, 0x01, 0x00, 0x80, 0x00]).await.unwrap();
+            let mut message = Vec::new();
+            assert!(matches!(receiver.receive_data(&mut message).await, Ok(Data::Text(0))));
+            assert!(matches!(receiver.receive_data(&mut message).await, Ok(Data::Binary(0))));
+            assert!(matches!(receiver.receive_data(&mut message).await, Ok(Data::Text(0))));
+            assert!(message.is_empty())
+        })
+    }
+
+    #[test]
+    fn validate_utf8_fragments() {
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.set_utf8_policy(Utf8Policy::Strict);
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            // "a€" with the euro sign split across three fragments.
+            remote.write_all(&[0x01, 0x02, b'a', 0xE2, 0x00, 0x01, 0x82, 0x80, 0x01, 0xAC]).await.unwrap();
+            let mut message = Vec::new();
+            assert!(matches!(receiver.receive_data(&mut message).await, Ok(Data::Text(4))));
+            assert_eq!("a€".as_bytes(), &message[..]);
+
+            // An invalid fragment fails without waiting for the rest of the message.
+            remote.write_all(&[0x01, 0x02, b'a', 0xFF]).await.unwrap();
+            let future = receiver.receive_data(&mut message);
+            futures::pin_mut!(future);
+            assert!(matches!(futures::poll!(future), Poll::Ready(Err(Error::Utf8(_)))))
+        })
+    }
+
+    #[test]
+    fn going_away_after_pending_messages() {
+        let (a, mut b) = duplex();
+        let mut builder = Builder::new(BufWriter::new(a), Mode::Server);
+        builder.set_auto_flush(false);
+        let (mut sender, _receiver) = builder.finish();
+        block_on(async {
+            sender.send_text("last words").await.unwrap();
+            sender.close_with_reason(1001, "shutting down").await.unwrap();
+            let (header, payload) = read_frame(&mut b).await;
+            assert_eq!((OpCode::Text, &b"last words"[..]), (header.opcode(), &payload[..]));
+            let (header, payload) = read_frame(&mut b).await;
+            assert_eq!(OpCode::Close, header.opcode());
+            assert_eq!(b"\x03\xe9shutting down", &payload[..])
+        })
+    }
+
+    #[test]
+    fn message_stream() {
+        let (a, b) = duplex();
+        let (mut sender, _) = Builder::new(a, Mode::Client).finish();
+        let (_, receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            sender.send_text("hello").await.unwrap();
+            sender.send_ping(ByteSlice125::try_from(&b"ping"[..]).unwrap()).await.unwrap();
+            sender.send_binary(&[1, 2, 3]).await.unwrap();
+            sender.send_text("").await.unwrap();
+            sender.close().await.unwrap();
+            let messages: Vec<_> = receiver.into_stream().collect().await;
+            assert!(matches!(&messages[..], [
+                Ok(Message::Text(t1)),
+                Ok(Message::Binary(b)),
+                Ok(Message::Text(t2))
+            ] if t1 == "hello" && b[..] == [1, 2, 3] && t2.is_empty()))
+        })
+    }
+
+    #[test]
+    fn exact_wire_bytes() {
+        let ping = || ByteSlice125::try_from(&b"hi"[..]).unwrap();
+        let (a, mut b) = duplex();
+        let (mut sender, _receiver) = Builder::new(a, Mode::Server).finish();
+        block_on(async {
+            sender.send_text("hi").await.unwrap();
+            sender.send_binary(b"hi").await.unwrap();
+            sender.send_ping(ping()).await.unwrap();
+            sender.send_pong(ping()).await.unwrap();
+            sender.close().await.unwrap();
+            let mut bytes = Vec::new();
+            b.read_to_end(&mut bytes).await.unwrap();
+            assert_eq!(&b"\x81\x02hi\x82\x02hi\x89\x02hi\x8A\x02hi\x88\x02\x03\xe8"[..], &bytes[..])
+        });
+
+        // Clients mask with a random key, except for premasked data.
+        let (a, mut b) = duplex();
+        let (mut sender, _receiver) = Builder::new(a, Mode::Client).finish();
+        let masked: Vec<u8> = b"hi".iter().zip(&[0x37, 0xfa]).map(|(b, m)| b ^ m).collect();
+        block_on(async {
+            sender.send_premasked(OpCode::Binary, 0x37fa213d, &masked).await.unwrap();
+            sender.abort().await.unwrap();
+            let mut bytes = Vec::new();
+            b.read_to_end(&mut bytes).await.unwrap();
+            assert_eq!(&[0x82, 0x82, 0x37, 0xfa, 0x21, 0x3d, masked[0], masked[1]], &bytes[..])
+        })
+    }
+
+    #[test]
+    fn reassembly_progress() {
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.set_max_message_size(1024);
+        let (_, mut receiver) = builder.finish();
+        let progress = receiver.progress();
+        assert_eq!(None, progress.get());
+        block_on(async {
+            remote.write_all(&[0x02, 0x02, 1, 2, 0x00, 0x01, 3]).await.unwrap();
+            let mut message = Vec::new();
+            let future = receiver.receive_data(&mut message);
+            futures::pin_mut!(future);
+            assert!(futures::poll!(future.as_mut()).is_pending());
+            assert_eq!(Some((3, 1024)), progress.get());
+            remote.write_all(&[0x80, 0x01, 4]).await.unwrap();
+            assert!(matches!(future.await, Ok(Data::Binary(4))));
+            assert_eq!(None, progress.get())
+        })
+    }
+
+    #[test]
+    fn strict_and_lenient() {
+        async fn receive(configure: fn(&mut Builder<crate::test_util::Endpoint>)) -> Result<Data, Error> {
+            let (mut remote, local) = duplex();
+            let mut builder = Builder::new(local, Mode::Client);
+            configure(&mut builder);
+            let (_, mut receiver) = builder.finish();
+            // Binary message of one byte with a two byte length.
+            remote.write_all(&[0x82, 0x7E, 0x00, 0x01, 0xFF]).await.unwrap();
+            let mut message = Vec::new();
+            receiver.receive_data(&mut message).await
+        }
+        block_on(async {
+            assert!(matches! {
+                receive(|b| { b.strict(); }).await,
+                Err(Error::Codec(base::Error::NonMinimalLength))
+            });
+            assert!(matches!(receive(|b| { b.lenient(); }).await, Ok(Data::Binary(1))));
+            assert!(matches!(receive(|b| { b.strict().set_require_minimal_length(false); }).await, Ok(Data::Binary(1))))
+        })
+    }
+}
@@ -8,6 +8,7 @@
 
 //! Types describing various forms of payload data.
 
+use bytes::Bytes;
 use std::{convert::TryFrom, fmt};
 
 /// Data received from the remote end.
@@ -22,12 +23,12 @@ pub enum Incoming<'a> {
 impl Incoming<'_> {
     /// Is this text or binary data?
     pub fn is_data(&self) -> bool {
-        if let Incoming::Data(_) = self { true } else { false }
+        matches!(self, Incoming::Data(_))
     }
 
     /// Is this a PONG?
     pub fn is_pong(&self) -> bool {
-        if let Incoming::Pong(_) = self { true } else { false }
+        matches!(self, Incoming::Pong(_))
     }
 
     /// Is this text data?
@@ -55,6 +56,11 @@ impl Incoming<'_> {
             Incoming::Pong(d) => d.len()
         }
     }
+
+    /// Is the data empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -68,12 +74,12 @@ pub enum Data {
 impl Data {
     /// Is this text data?
     pub fn is_text(&self) -> bool {
-        if let Data::Text(_) = self { true } else { false }
+        matches!(self, Data::Text(_))
     }
 
     /// Is this binary data?
     pub fn is_binary(&self) -> bool {
-        if let Data::Binary(_) = self { true } else { false }
+        matches!(self, Data::Binary(_))
     }
 
     /// The length of data (number of bytes).
@@ -83,6 +89,33 @@ impl Data {
             Data::Binary(n) => *n
         }
     }
+
+    /// Is the data empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A complete message received from the remote end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A text message.
+    Text(String),
+    /// A binary message.
+    Binary(Bytes)
+}
+
+/// Data to send to the remote end.
+#[derive(Debug)]
+pub enum Outgoing<'a> {
+    /// Textual data.
+    Text(&'a str),
+    /// Binary data.
+    Binary(&'a [u8]),
+    /// Data to send with a PING control frame.
+    Ping(ByteSlice125<'a>),
+    /// Data to send with an unsolicited PONG control frame.
+    Pong(ByteSlice125<'a>)
 }
 
 /// Wrapper type which restricts the length of its byte slice to 125 bytes.
@@ -27,21 +27,21 @@ use std::{borrow::Cow, fmt};
 ///
 /// 1. All extensions should consider themselves as disabled but available.
 /// 2. When receiving a handshake request from a client, for each extension
-/// with a matching name, [`Extension::configure`] will be applied to the
-/// request parameters. The extension may internally enable itself.
+///    with a matching name, [`Extension::configure`] will be applied to the
+///    request parameters. The extension may internally enable itself.
 /// 3. When sending back the response, for each extension whose
-/// [`Extension::is_enabled`] returns true, the extension name and its
-/// parameters (as returned by [`Extension::params`]) will be included in the
-/// response.
+///    [`Extension::is_enabled`] returns true, the extension name and its
+///    parameters (as returned by [`Extension::params`]) will be included in the
+///    response.
 ///
 /// # Client
 ///
 /// 1. All extensions should consider themselves as disabled but available.
 /// 2. When creating the handshake request, all extensions and its parameters
-/// (as returned by [`Extension::params`]) will be included in the request.
+///    (as returned by [`Extension::params`]) will be included in the request.
 /// 3. When receiving the response from the server, for every extension with
-/// a matching name in the response, [`Extension::configure`] will be applied
-/// to the response parameters. The extension may internally enable itself.
+///    a matching name in the response, [`Extension::configure`] will be applied
+///    to the response parameters. The extension may internally enable itself.
 ///
 /// After this handshake phase, extensions have been configured and are
 /// potentially enabled. Enabled extensions can then be used for further base
@@ -54,7 +54,7 @@ pub trait Extension: std::fmt::Debug {
     fn name(&self) -> &str;
 
     /// The parameters this extension wants to send for negotiation.
-    fn params(&self) -> &[Param];
+    fn params(&self) -> &[Param<'_>];
 
     /// Configure this extension with the parameters received from negotiation.
     fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError>;
@@ -64,14 +64,51 @@ pub trait Extension: std::fmt::Debug {
 
     /// Decode a frame.
     ///
-    /// The frame header is given, as well as the accumulated payload data, i.e.
-    /// the concatenated payload data of all message fragments.
+    /// The frame header is given, as well as the application data of this
+    /// frame, i.e. its payload without the extension data of any extension
+    /// (cf. [`Extension::extension_data_len`]). Every frame of a fragmented
+    /// message is passed separately, with only its own payload data.
+    /// Extensions which act on complete messages have to keep the fragments
+    /// until the last one (with FIN) arrives.
+    ///
+    /// Errors caused by the remote should be returned as [`PeerError`]s.
     fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError>;
 
+    /// The number of bytes of extension data at the start of a frame's payload.
+    ///
+    /// Before a received frame is decoded, these bytes are split off its
+    /// payload and given to [`Extension::decode_extension_data`]. With
+    /// several extensions, their extension data follows each other in the
+    /// order the extensions were added. Frames too short to hold the
+    /// extension data are rejected. By default there is no extension data.
+    fn extension_data_len(&self, header: &Header) -> usize {
+        let _ = header;
+        0
+    }
+
+    /// Process the extension data of a received frame.
+    ///
+    /// This is called before [`Extension::decode`] with the bytes announced
+    /// by [`Extension::extension_data_len`].
+    fn decode_extension_data(&mut self, header: &Header, data: &[u8]) -> Result<(), BoxedError> {
+        let _ = (header, data);
+        Ok(())
+    }
+
     /// The reserved bits this extension uses.
     fn reserved_bits(&self) -> (bool, bool, bool) {
         (false, false, false)
     }
+
+    /// Set the maximum size of a decoded message.
+    ///
+    /// This is called with the connection's maximum message size once the
+    /// connection is configured. Extensions which enlarge payload data during
+    /// decoding, e.g. by decompressing it, may stop decoding as soon as more
+    /// than `max` bytes have been produced. The message is rejected then.
+    fn set_max_message_size(&mut self, max: usize) {
+        let _ = max;
+    }
 }
 
 impl<E: Extension + ?Sized> Extension for Box<E> {
@@ -83,7 +120,7 @@ impl<E: Extension + ?Sized> Extension for Box<E> {
         (**self).name()
     }
 
-    fn params(&self) -> &[Param] {
+    fn params(&self) -> &[Param<'_>] {
         (**self).params()
     }
 
@@ -99,9 +136,21 @@ impl<E: Extension + ?Sized> Extension for Box<E> {
         (**self).decode(header, data)
     }
 
+    fn extension_data_len(&self, header: &Header) -> usize {
+        (**self).extension_data_len(header)
+    }
+
+    fn decode_extension_data(&mut self, header: &Header, data: &[u8]) -> Result<(), BoxedError> {
+        (**self).decode_extension_data(header, data)
+    }
+
     fn reserved_bits(&self) -> (bool, bool, bool) {
         (**self).reserved_bits()
     }
+
+    fn set_max_message_size(&mut self, max: usize) {
+        (**self).set_max_message_size(max)
+    }
 }
 
 /// Extension parameter (used for negotiation).
@@ -155,3 +204,34 @@ impl<'a> Param<'a> {
     }
 }
 
+/// An error an extension returns if the remote is at fault.
+///
+/// Other errors of [`Extension::decode`] are considered internal errors
+/// and close the connection with status code 1011.
+#[derive(Debug)]
+pub enum PeerError {
+    /// The remote violated the protocol, e.g. by setting a reserved bit on
+    /// the wrong frame (status code 1002).
+    Protocol(Cow<'static, str>),
+    /// The remote sent invalid payload data, e.g. corrupt compressed data
+    /// (status code 1007).
+    InvalidData(BoxedError)
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerError::Protocol(e) => write!(f, "protocol error: {}", e),
+            PeerError::InvalidData(e) => write!(f, "invalid data: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for PeerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PeerError::Protocol(_) => None,
+            PeerError::InvalidData(e) => Some(&**e)
+        }
+    }
+}
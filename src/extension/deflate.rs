@@ -16,10 +16,10 @@ use crate::{
     Storage,
     base::{Header, OpCode},
     connection::Mode,
-    extension::{Extension, Param}
+    extension::{Extension, Param, PeerError}
 };
-use flate2::{Compress, Compression, FlushCompress, Status, write::DeflateDecoder};
-use std::{convert::TryInto, io::{self, Write}, mem};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::{convert::TryInto, mem};
 
 const SERVER_NO_CONTEXT_TAKEOVER: &str = "server_no_context_takeover";
 const SERVER_MAX_WINDOW_BITS: &str = "server_max_window_bits";
@@ -29,8 +29,9 @@ const CLIENT_MAX_WINDOW_BITS: &str = "client_max_window_bits";
 
 /// The deflate extension type.
 ///
-/// The extension does currently not support max. window bits other than the
-/// default, which is 15 and will ask for no context takeover during handshake.
+/// By default a client asks for no context takeover in both directions
+/// during handshake, cf. [`Deflate::set_server_no_context_takeover`] and
+/// [`Deflate::set_client_no_context_takeover`].
 #[derive(Debug)]
 pub struct Deflate {
     mode: Mode,
@@ -39,7 +40,16 @@ pub struct Deflate {
     params: Vec<Param<'static>>,
     our_max_window_bits: u8,
     their_max_window_bits: u8,
-    await_last_fragment: bool
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    our_no_context_takeover: bool,
+    their_no_context_takeover: bool,
+    max_message_size: usize,
+    encoder: Option<Compress>,
+    decoder: Option<Decompress>,
+    await_last_fragment: bool,
+    /// The compressed payload of the fragments received so far.
+    fragments: Vec<u8>
 }
 
 impl Deflate {
@@ -47,13 +57,11 @@ impl Deflate {
     pub fn new(mode: Mode) -> Self {
         let params = match mode {
             Mode::Server => Vec::new(),
-            Mode::Client => {
-                let mut params = Vec::new();
-                params.push(Param::new(SERVER_NO_CONTEXT_TAKEOVER));
-                params.push(Param::new(CLIENT_NO_CONTEXT_TAKEOVER));
-                params.push(Param::new(CLIENT_MAX_WINDOW_BITS));
-                params
-            }
+            Mode::Client => vec![
+                Param::new(SERVER_NO_CONTEXT_TAKEOVER),
+                Param::new(CLIENT_NO_CONTEXT_TAKEOVER),
+                Param::new(CLIENT_MAX_WINDOW_BITS)
+            ]
         };
         Deflate {
             mode,
@@ -62,7 +70,51 @@ impl Deflate {
             params,
             our_max_window_bits: 15,
             their_max_window_bits: 15,
-            await_last_fragment: false
+            server_no_context_takeover: mode == Mode::Client,
+            client_no_context_takeover: mode == Mode::Client,
+            our_no_context_takeover: true,
+            their_no_context_takeover: true,
+            max_message_size: usize::MAX,
+            encoder: None,
+            decoder: None,
+            await_last_fragment: false,
+            fragments: Vec::new()
+        }
+    }
+
+    /// Ask for the server to reset its compression context after each message.
+    ///
+    /// In client mode this controls whether "server_no_context_takeover" is
+    /// offered (default: true). If offered, the server has to accept it.
+    /// In server mode, "server_no_context_takeover" is included in the response
+    /// even if the client did not offer it (default: false).
+    pub fn set_server_no_context_takeover(&mut self, no_takeover: bool) {
+        self.server_no_context_takeover = no_takeover;
+        if self.mode == Mode::Client {
+            self.set_offer(SERVER_NO_CONTEXT_TAKEOVER, no_takeover)
+        }
+    }
+
+    /// Ask for the client to reset its compression context after each message.
+    ///
+    /// In client mode this controls whether "client_no_context_takeover" is
+    /// offered (default: true). In server mode, "client_no_context_takeover"
+    /// is included in the response even if the client did not offer it
+    /// (default: false).
+    pub fn set_client_no_context_takeover(&mut self, no_takeover: bool) {
+        self.client_no_context_takeover = no_takeover;
+        if self.mode == Mode::Client {
+            self.set_offer(CLIENT_NO_CONTEXT_TAKEOVER, no_takeover)
+        }
+    }
+
+    /// Add or remove a parameter without value from the client's offer.
+    fn set_offer(&mut self, name: &'static str, include: bool) {
+        let present = self.params.iter().any(|p| p.name() == name);
+        if include && !present {
+            self.params.push(Param::new(name))
+        } else if !include && present {
+            self.params.retain(|p| p.name() != name)
         }
     }
 
@@ -111,7 +163,7 @@ impl Deflate {
 
     fn set_their_max_window_bits(&mut self, p: &Param, expected: Option<u8>) -> Result<(), ()> {
         if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
-            if v < 8 || v > 15 {
+            if !(8 ..= 15).contains(&v) {
                 log::debug!("invalid {}: {} (expected range: 8 ..= 15)", p.name(), v);
                 return Err(())
             }
@@ -136,7 +188,7 @@ impl Extension for Deflate {
         self.enabled
     }
 
-    fn params(&self) -> &[Param] {
+    fn params(&self) -> &[Param<'_>] {
         &self.params
     }
 
@@ -144,11 +196,13 @@ impl Extension for Deflate {
         match self.mode {
             Mode::Server => {
                 self.params.clear();
+                let mut server_no_context_takeover = self.server_no_context_takeover;
+                let mut client_no_context_takeover = self.client_no_context_takeover;
                 for p in params {
                     log::trace!("configure server with: {}", p);
                     match p.name() {
                         CLIENT_MAX_WINDOW_BITS =>
-                            if self.set_their_max_window_bits(&p, None).is_err() {
+                            if self.set_their_max_window_bits(p, None).is_err() {
                                 // we just accept the client's offer as is => no need to reply
                                 return Ok(())
                             }
@@ -156,7 +210,7 @@ impl Extension for Deflate {
                             if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
                                 // The RFC allows 8 to 15 bits, but due to zlib limitations we
                                 // only support 9 to 15.
-                                if v < 9 || v > 15 {
+                                if !(9 ..= 15).contains(&v) {
                                     log::debug!("unacceptable server_max_window_bits: {}", v);
                                     return Ok(())
                                 }
@@ -169,33 +223,43 @@ impl Extension for Deflate {
                                 return Ok(())
                             }
                         }
-                        CLIENT_NO_CONTEXT_TAKEOVER =>
-                            self.params.push(Param::new(CLIENT_NO_CONTEXT_TAKEOVER)),
-                        SERVER_NO_CONTEXT_TAKEOVER =>
-                            self.params.push(Param::new(SERVER_NO_CONTEXT_TAKEOVER)),
+                        CLIENT_NO_CONTEXT_TAKEOVER => client_no_context_takeover = true,
+                        SERVER_NO_CONTEXT_TAKEOVER => server_no_context_takeover = true,
                         _ => {
                             log::debug!("{}: unknown parameter: {}", self.name(), p.name());
                             return Ok(())
                         }
                     }
                 }
+                if client_no_context_takeover {
+                    self.params.push(Param::new(CLIENT_NO_CONTEXT_TAKEOVER))
+                }
+                if server_no_context_takeover {
+                    self.params.push(Param::new(SERVER_NO_CONTEXT_TAKEOVER))
+                }
+                self.our_no_context_takeover = server_no_context_takeover;
+                self.their_no_context_takeover = client_no_context_takeover
             }
             Mode::Client => {
+                let offered_server_bits = self.params.iter().any(|p| p.name() == SERVER_MAX_WINDOW_BITS);
+                let mut server_bits = false;
                 let mut server_no_context_takeover = false;
+                let mut client_no_context_takeover = self.client_no_context_takeover;
                 for p in params {
                     log::trace!("configure client with: {}", p);
                     match p.name() {
                         SERVER_NO_CONTEXT_TAKEOVER => server_no_context_takeover = true,
-                        CLIENT_NO_CONTEXT_TAKEOVER => {} // must be supported
+                        CLIENT_NO_CONTEXT_TAKEOVER => client_no_context_takeover = true, // must be supported
                         SERVER_MAX_WINDOW_BITS => {
                             let expected = Some(self.their_max_window_bits);
-                            if self.set_their_max_window_bits(&p, expected).is_err() {
+                            if self.set_their_max_window_bits(p, expected).is_err() {
                                 return Ok(())
                             }
+                            server_bits = true
                         }
                         CLIENT_MAX_WINDOW_BITS =>
                             if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
-                                if v < 8 || v > 15 {
+                                if !(8 ..= 15).contains(&v) {
                                     log::debug!("unacceptable client_max_window_bits: {}", v);
                                     return Ok(())
                                 }
@@ -210,13 +274,27 @@ impl Extension for Deflate {
                         }
                     }
                 }
-                if !server_no_context_takeover {
+                if offered_server_bits && !server_bits {
+                    log::debug!("{}: server did not confirm max. window bits", self.name());
+                    return Ok(())
+                }
+                if self.server_no_context_takeover && !server_no_context_takeover {
                     log::debug!("{}: server did not confirm no context takeover", self.name());
                     return Ok(())
                 }
+                self.our_no_context_takeover = client_no_context_takeover;
+                self.their_no_context_takeover = server_no_context_takeover
             }
         }
         self.enabled = true;
+        log::debug! {
+            "{}: enabled (max. window bits: ours = {}, theirs = {}; no context takeover: ours = {}, theirs = {})",
+            self.name(),
+            self.our_max_window_bits,
+            self.their_max_window_bits,
+            self.our_no_context_takeover,
+            self.their_no_context_takeover
+        }
         Ok(())
     }
 
@@ -224,22 +302,38 @@ impl Extension for Deflate {
         (true, false, false)
     }
 
+    fn set_max_message_size(&mut self, max: usize) {
+        self.max_message_size = max
+    }
+
     fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
-        if data.is_empty() {
-            return Ok(())
+        // Only the first frame of a compressed message has RSV1 set (RFC 7692, 6.1).
+        if header.opcode() == OpCode::Continue && header.is_rsv1() {
+            log::debug!("deflate: RSV1 set on {}", header);
+            return Err(Box::new(PeerError::Protocol("RSV1 set on continuation frame".into())))
         }
 
+        // A compressed message is decompressed as a whole, so fragments are
+        // kept until the last one arrives.
         match header.opcode() {
             OpCode::Binary | OpCode::Text if header.is_rsv1() => {
                 if !header.is_fin() {
                     self.await_last_fragment = true;
+                    self.fragments.clear();
+                    self.fragments.append(data);
                     log::trace!("deflate: not decoding {}; awaiting last fragment", header);
                     return Ok(())
                 }
                 log::trace!("deflate: decoding {}", header)
             }
-            OpCode::Continue if header.is_fin() && self.await_last_fragment => {
+            OpCode::Continue if self.await_last_fragment => {
+                self.fragments.append(data);
+                if !header.is_fin() {
+                    log::trace!("deflate: not decoding {}; awaiting last fragment", header);
+                    return Ok(())
+                }
                 self.await_last_fragment = false;
+                mem::swap(data, &mut self.fragments);
                 log::trace!("deflate: decoding {}", header)
             }
             _ => {
@@ -248,13 +342,43 @@ impl Extension for Deflate {
             }
         }
 
+        if data.is_empty() {
+            return Ok(())
+        }
+
         // Restore LEN and NLEN:
         data.extend_from_slice(&[0, 0, 0xFF, 0xFF]); // cf. RFC 7692, 7.2.2
 
+        let bits = self.their_max_window_bits;
+        let decoder = self.decoder.get_or_insert_with(|| Decompress::new_with_window_bits(false, bits));
+        if self.their_no_context_takeover {
+            decoder.reset(false)
+        }
+
         self.buffer.clear();
-        let mut decoder = DeflateDecoder::new(&mut self.buffer);
-        decoder.write_all(&data)?;
-        decoder.finish()?;
+        self.buffer.reserve(2 * data.len());
+
+        // Decompress all input bytes, growing the output buffer as needed.
+        // Once the maximum message size is exceeded we stop, leaving it to
+        // the connection to reject the message.
+        let start = decoder.total_in();
+        while self.buffer.len() <= self.max_message_size {
+            let i: usize = (decoder.total_in() - start).try_into()?;
+            let status = decoder.decompress_vec(&data[i ..], &mut self.buffer, FlushDecompress::Sync)
+                .map_err(|e| PeerError::InvalidData(Box::new(e)))?;
+            let consumed = decoder.total_in() - start == as_u64(data.len());
+            let has_room = self.buffer.len() < self.buffer.capacity();
+            match status {
+                Status::StreamEnd => break,
+                Status::Ok | Status::BufError if consumed && has_room => break,
+                Status::Ok => self.buffer.reserve(4096),
+                Status::BufError if !has_room => self.buffer.reserve(4096),
+                Status::BufError => {
+                    log::debug!("deflate: decompression stalled");
+                    return Err(Box::new(PeerError::InvalidData("decompression stalled".into())))
+                }
+            }
+        }
         mem::swap(data, &mut self.buffer);
 
         header.set_rsv1(false);
@@ -268,6 +392,16 @@ impl Extension for Deflate {
             return Ok(())
         }
 
+        if header.is_rsv1() {
+            // The data has already been compressed. The remote's decompression
+            // context would include this message, but ours would not.
+            if !self.our_no_context_takeover {
+                return Err("pre-compressed data requires no context takeover".into())
+            }
+            log::trace!("deflate: not encoding pre-compressed {}", header);
+            return Ok(())
+        }
+
         if let OpCode::Binary | OpCode::Text = header.opcode() {
             log::trace!("deflate: encoding {}", header)
         } else {
@@ -278,12 +412,18 @@ impl Extension for Deflate {
         self.buffer.clear();
         self.buffer.reserve(data.as_ref().len());
 
-        let mut encoder =
-            Compress::new_with_window_bits(Compression::fast(), false, self.our_max_window_bits);
+        let bits = self.our_max_window_bits;
+        let encoder = self.encoder.get_or_insert_with(|| {
+            Compress::new_with_window_bits(Compression::fast(), false, bits)
+        });
+        if self.our_no_context_takeover {
+            encoder.reset()
+        }
 
         // Compress all input bytes.
-        while encoder.total_in() < as_u64(data.as_ref().len()) {
-            let i: usize = encoder.total_in().try_into()?;
+        let start = encoder.total_in();
+        while encoder.total_in() - start < as_u64(data.as_ref().len()) {
+            let i: usize = (encoder.total_in() - start).try_into()?;
             match encoder.compress_vec(&data.as_ref()[i ..], &mut self.buffer, FlushCompress::None)? {
                 Status::BufError => self.buffer.reserve(4096),
                 Status::Ok => continue,
@@ -304,7 +444,7 @@ impl Extension for Deflate {
         // If we still have not seen the empty deflate block appended, something is wrong.
         if !self.buffer.ends_with(&[0, 0, 0xFF, 0xFF]) {
             log::error!("missing 00 00 FF FF");
-            return Err(io::Error::new(io::ErrorKind::Other, "missing 00 00 FF FF").into())
+            return Err("missing 00 00 FF FF".into())
         }
 
         self.buffer.truncate(self.buffer.len() - 4); // Remove 00 00 FF FF; cf. RFC 7692, 7.2.1
@@ -320,3 +460,364 @@ impl Extension for Deflate {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::{Storage, base::{Codec, Header, OpCode}, extension::Extension};
+    use crate::test_util::{capture_logs, duplex, read_frame, Endpoint};
+    use crate::connection::{Builder, Error, Mode, Receiver};
+    use futures::{executor::block_on, prelude::*};
+    use crate::extension::Param;
+    use super::{CLIENT_MAX_WINDOW_BITS, CLIENT_NO_CONTEXT_TAKEOVER, Deflate};
+    use super::{SERVER_MAX_WINDOW_BITS, SERVER_NO_CONTEXT_TAKEOVER};
+
+    fn negotiate(client: &mut Deflate, server: &mut Deflate) {
+        let offer = client.params().to_vec();
+        server.configure(&offer).unwrap();
+        assert!(server.is_enabled());
+        let response = server.params().to_vec();
+        client.configure(&response).unwrap();
+        assert!(client.is_enabled())
+    }
+
+    /// Send `message` from one extension to the other and return its compressed size.
+    fn transfer(from: &mut Deflate, to: &mut Deflate, message: &[u8]) -> usize {
+        let mut header = Header::new(OpCode::Binary);
+        let mut data = Storage::Shared(message);
+        from.encode(&mut header, &mut data).unwrap();
+        let mut payload = data.as_ref().to_vec();
+        let len = payload.len();
+        to.decode(&mut header, &mut payload).unwrap();
+        assert_eq!(message, &payload[..]);
+        len
+    }
+
+    /// Check if the sender of `message` keeps its context between messages.
+    fn keeps_context(from: &mut Deflate, to: &mut Deflate, message: &[u8]) -> bool {
+        let first = transfer(from, to, message);
+        let second = transfer(from, to, message);
+        assert!(second <= first);
+        second < first
+    }
+
+    #[test]
+    fn context_takeover_combinations() {
+        // Bytes which do not compress well on their own.
+        let message: Vec<u8> = (0 .. 256_u32).map(|i| (i.wrapping_mul(157) ^ (i >> 3)) as u8).collect();
+        for &(server_no_takeover, client_no_takeover) in &[(true, true), (true, false), (false, true), (false, false)] {
+            let mut client = Deflate::new(Mode::Client);
+            client.set_server_no_context_takeover(server_no_takeover);
+            client.set_client_no_context_takeover(client_no_takeover);
+            let mut server = Deflate::new(Mode::Server);
+            negotiate(&mut client, &mut server);
+            assert_eq!(!client_no_takeover, keeps_context(&mut client, &mut server, &message));
+            assert_eq!(!server_no_takeover, keeps_context(&mut server, &mut client, &message))
+        }
+    }
+
+    #[test]
+    fn server_requests_no_context_takeover() {
+        let message: Vec<u8> = (0 .. 256_u32).map(|i| (i.wrapping_mul(157) ^ (i >> 3)) as u8).collect();
+        let mut client = Deflate::new(Mode::Client);
+        client.set_server_no_context_takeover(false);
+        client.set_client_no_context_takeover(false);
+        let mut server = Deflate::new(Mode::Server);
+        server.set_client_no_context_takeover(true);
+        negotiate(&mut client, &mut server);
+        assert!(!keeps_context(&mut client, &mut server, &message));
+        assert!(keeps_context(&mut server, &mut client, &message))
+    }
+
+    #[test]
+    fn client_requires_server_confirmation() {
+        let mut client = Deflate::new(Mode::Client);
+        client.configure(&[]).unwrap();
+        assert!(!client.is_enabled())
+    }
+
+    #[test]
+    fn send_precompressed() {
+        let mut client = Deflate::new(Mode::Client);
+        let mut server = Deflate::new(Mode::Server);
+        negotiate(&mut client, &mut server);
+
+        // Compress the message with a separate, identically configured instance.
+        let mut compressor = Deflate::new(Mode::Client);
+        negotiate(&mut compressor, &mut Deflate::new(Mode::Server));
+        let mut header = Header::new(OpCode::Text);
+        let mut data = Storage::Shared(b"hello hello hello");
+        compressor.encode(&mut header, &mut data).unwrap();
+        let compressed = data.as_ref().to_vec();
+
+        let (a, b) = duplex();
+        let mut builder = Builder::new(a, Mode::Client);
+        builder.add_extensions(std::iter::once(Box::new(client) as Box<_>));
+        let (mut sender, _) = builder.finish();
+        let mut builder = Builder::new(b, Mode::Server);
+        builder.add_extensions(std::iter::once(Box::new(server) as Box<_>));
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            for _ in 0 .. 2 {
+                sender.send_precompressed(OpCode::Text, &compressed).await.unwrap();
+                let mut message = Vec::new();
+                assert!(receiver.receive_data(&mut message).await.unwrap().is_text());
+                assert_eq!(b"hello hello hello", &message[..])
+            }
+        })
+    }
+
+    #[test]
+    fn send_precompressed_requires_deflate() {
+        let (a, _b) = duplex();
+        let (mut sender, _) = Builder::new(a, Mode::Client).finish();
+        let result = block_on(sender.send_precompressed(OpCode::Binary, &[0x01]));
+        assert!(matches!(result, Err(Error::Extension(_))))
+    }
+
+    #[test]
+    fn precompressed_requires_no_context_takeover() {
+        let mut client = Deflate::new(Mode::Client);
+        client.set_client_no_context_takeover(false);
+        negotiate(&mut client, &mut Deflate::new(Mode::Server));
+        let mut header = Header::new(OpCode::Binary);
+        header.set_rsv1(true);
+        assert!(client.encode(&mut header, &mut Storage::Shared(&[0x01])).is_err())
+    }
+
+    /// Create a client receiver using deflate, reading from the returned endpoint.
+    fn deflate_receiver() -> (Endpoint, Receiver<Endpoint>) {
+        let mut client = Deflate::new(Mode::Client);
+        negotiate(&mut client, &mut Deflate::new(Mode::Server));
+        let (remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.add_extensions(std::iter::once(Box::new(client) as Box<_>));
+        let (_, receiver) = builder.finish();
+        (remote, receiver)
+    }
+
+    #[test]
+    fn uncompressed_messages_pass_through() {
+        let (mut remote, mut receiver) = deflate_receiver();
+        block_on(async {
+            remote.write_all(&[0x01, 0x01, b'h', 0x80, 0x01, b'i']).await.unwrap();
+            let mut message = Vec::new();
+            assert!(receiver.receive_data(&mut message).await.unwrap().is_text());
+            assert_eq!(b"hi", &message[..])
+        })
+    }
+
+    #[test]
+    fn reject_rsv1_on_continuation() {
+        // RSV1 on the last and on an intermediate continuation frame.
+        for frames in &[&[0x41, 0x01, 0x00, 0xC0, 0x01, 0x00][..], &[0x41, 0x01, 0x00, 0x40, 0x00]] {
+            let (mut remote, mut receiver) = deflate_receiver();
+            block_on(async {
+                remote.write_all(frames).await.unwrap();
+                let mut message = Vec::new();
+                assert!(matches!(receiver.receive_data(&mut message).await, Err(Error::Extension(_))))
+            })
+        }
+    }
+
+    #[test]
+    fn reject_decompression_bomb() {
+        let mut client = Deflate::new(Mode::Client);
+        let mut server = Deflate::new(Mode::Server);
+        negotiate(&mut client, &mut server);
+
+        let zeros = vec![0; 1024 * 1024];
+        let mut header = Header::new(OpCode::Binary);
+        let mut data = Storage::Shared(&zeros);
+        server.encode(&mut header, &mut data).unwrap();
+        let mut frame = Codec::new().encode_header(&header).to_vec();
+        frame.extend_from_slice(data.as_ref());
+        assert!(frame.len() < 64 * 1024); // well below the limit
+
+        let (mut remote, local) = duplex();
+        let mut builder = Builder::new(local, Mode::Client);
+        builder.add_extensions(std::iter::once(Box::new(client) as Box<_>)).set_max_message_size(64 * 1024);
+        let (_, mut receiver) = builder.finish();
+        block_on(async {
+            remote.write_all(&frame).await.unwrap();
+            let mut message = Vec::new();
+            assert!(matches!(receiver.receive_data(&mut message).await, Err(Error::MessageTooLarge { .. })));
+            assert!(message.is_empty());
+            let (header, payload) = read_frame(&mut remote).await;
+            assert_eq!(OpCode::Close, header.opcode());
+            assert_eq!(1009_u16.to_be_bytes(), &payload[..])
+        })
+    }
+
+    #[test]
+    fn server_without_extensions_ignores_offer() {
+        use crate::handshake::{Client, Server, ServerResponse, server::Response};
+
+        let (a, b) = duplex();
+        let mut client = Client::new(a, "localhost", "/");
+        client.add_extension(Box::new(Deflate::new(Mode::Client)));
+        let mut server = Server::new(b);
+        block_on(async {
+            let (response, ()) = future::join(client.handshake(), async {
+                let request = server.receive_request().await.unwrap();
+                let response = Response::Accept { key: request.key(), protocol: None };
+                server.send_response(&response).await.unwrap()
+            }).await;
+            assert!(matches!(response, Ok(ServerResponse::Accepted { .. })));
+            assert!(client.drain_extensions().all(|e| !e.is_enabled()));
+
+            let (_, mut receiver) = server.into_builder().finish();
+            let mut socket = client.into_inner();
+            socket.write_all(&[0xC1, 0x81, 0, 0, 0, 0, b'x']).await.unwrap();
+            let mut message = Vec::new();
+            assert!(matches! {
+                receiver.receive_data(&mut message).await,
+                Err(Error::Codec(crate::base::Error::InvalidReservedBit(1)))
+            })
+        })
+    }
+
+    #[test]
+    fn negotiate_window_bits() {
+        let message: Vec<u8> = (0 .. 4096_u32).map(|i| (i.wrapping_mul(157) ^ (i >> 5)) as u8).collect();
+        let mut client = Deflate::new(Mode::Client);
+        client.set_max_server_window_bits(9);
+        client.set_max_client_window_bits(10);
+        let mut server = Deflate::new(Mode::Server);
+        negotiate(&mut client, &mut server);
+        assert_eq!((9, 10), (server.our_max_window_bits, server.their_max_window_bits));
+        assert_eq!((10, 9), (client.our_max_window_bits, client.their_max_window_bits));
+        transfer(&mut client, &mut server, &message);
+        transfer(&mut server, &mut client, &message);
+    }
+
+    #[test]
+    fn decline_invalid_window_bits() {
+        fn param(name: &'static str, value: &str) -> Param<'static> {
+            let mut p = Param::new(name);
+            p.set_value(Some(value.to_string()));
+            p
+        }
+        for value in &["7", "8", "16", "255", "x"] {
+            let mut server = Deflate::new(Mode::Server);
+            server.configure(&[param(SERVER_MAX_WINDOW_BITS, value)]).unwrap();
+            assert!(!server.is_enabled(), "server_max_window_bits = {}", value)
+        }
+        for value in &["7", "16"] {
+            let mut server = Deflate::new(Mode::Server);
+            server.configure(&[param(CLIENT_MAX_WINDOW_BITS, value)]).unwrap();
+            assert!(!server.is_enabled(), "client_max_window_bits = {}", value)
+        }
+
+        // The server must confirm our limit with the same or a smaller value.
+        let confirmed = [Param::new(SERVER_NO_CONTEXT_TAKEOVER), Param::new(CLIENT_NO_CONTEXT_TAKEOVER)];
+        for &(response, enabled) in &[(None, false), (Some("12"), false), (Some("11"), true), (Some("9"), true)] {
+            let mut client = Deflate::new(Mode::Client);
+            client.set_max_server_window_bits(11);
+            let mut params = confirmed.to_vec();
+            params.extend(response.map(|v| param(SERVER_MAX_WINDOW_BITS, v)));
+            client.configure(&params).unwrap();
+            assert_eq!(enabled, client.is_enabled(), "server_max_window_bits = {:?}", response)
+        }
+    }
+
+    #[test]
+    fn log_negotiated_parameters() {
+        let mut client = Deflate::new(Mode::Client);
+        client.set_max_client_window_bits(12);
+        let mut server = Deflate::new(Mode::Server);
+        let logs = capture_logs(|| negotiate(&mut client, &mut server));
+        let expected = [
+            // Server: it compresses with window bits 15 and the client with 12.
+            "permessage-deflate: enabled (max. window bits: ours = 15, theirs = 12; \
+             no context takeover: ours = true, theirs = true)",
+            "permessage-deflate: enabled (max. window bits: ours = 12, theirs = 15; \
+             no context takeover: ours = true, theirs = true)"
+        ];
+        for e in &expected {
+            assert!(logs.iter().any(|m| m == e), "{:?} not in {:?}", e, logs)
+        }
+    }
+
+    #[test]
+    fn decompress_fragmented_message() {
+        let message = b"a message compressed as a whole and sent in three fragments".repeat(4);
+        let mut server = Deflate::new(Mode::Server);
+        negotiate(&mut Deflate::new(Mode::Client), &mut server);
+        let mut header = Header::new(OpCode::Text);
+        let mut data = Storage::Shared(&message);
+        server.encode(&mut header, &mut data).unwrap();
+        let (first, rest) = data.as_ref().split_at(data.as_ref().len() / 3);
+        let (second, third) = rest.split_at(rest.len() / 2);
+        assert!(first.len() < 126 && second.len() < 126 && third.len() < 126);
+
+        let mut frames = vec![0x41, first.len() as u8];
+        frames.extend_from_slice(first);
+        frames.extend_from_slice(&[0x00, second.len() as u8]);
+        frames.extend_from_slice(second);
+        frames.extend_from_slice(&[0x80, third.len() as u8]);
+        frames.extend_from_slice(third);
+
+        let (mut remote, mut receiver) = deflate_receiver();
+        block_on(async {
+            remote.write_all(&frames).await.unwrap();
+            let mut received = Vec::new();
+            assert!(receiver.receive_data(&mut received).await.unwrap().is_text());
+            assert_eq!(message, received)
+        })
+    }
+
+    #[test]
+    fn negotiated_extensions() {
+        use crate::handshake::{Client, Server, server::Response};
+
+        let (a, b) = duplex();
+        let mut client = Client::new(a, "localhost", "/");
+        client.add_extension(Box::new(Deflate::new(Mode::Client)));
+        let mut server = Server::new(b);
+        server.add_extension(Box::new(Deflate::new(Mode::Server)));
+        assert_eq!(0, client.negotiated_extensions().count());
+        assert_eq!(0, server.negotiated_extensions().count());
+        block_on(async {
+            let (response, ()) = future::join(client.handshake(), async {
+                let request = server.receive_request().await.unwrap();
+                let response = Response::Accept { key: request.key(), protocol: None };
+                server.send_response(&response).await.unwrap()
+            }).await;
+            response.unwrap()
+        });
+        assert_eq!(vec!["permessage-deflate"], client.negotiated_extensions().collect::<Vec<_>>());
+        assert_eq!(vec!["permessage-deflate"], server.negotiated_extensions().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn decline_webkit_deflate_frame() {
+        use crate::handshake::{Server, server::Response};
+
+        for (offer, negotiated) in &[
+            ("x-webkit-deflate-frame; max_window_bits=10", None),
+            ("x-webkit-deflate-frame; no_context_takeover, permessage-deflate", Some("permessage-deflate"))
+        ] {
+            let (mut a, b) = duplex();
+            let mut server = Server::new(b);
+            server.add_extension(Box::new(Deflate::new(Mode::Server)));
+            let request = format! {
+                "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+                 Sec-WebSocket-Extensions: {}\r\n\r\n",
+                offer
+            };
+            block_on(async {
+                a.write_all(request.as_bytes()).await.unwrap();
+                let request = server.receive_request().await.unwrap();
+                let response = Response::Accept { key: request.key(), protocol: None };
+                server.send_response(&response).await.unwrap();
+            });
+            assert_eq!(*negotiated, server.negotiated_extensions().next());
+            drop(server);
+            let mut response = Vec::new();
+            block_on(a.read_to_end(&mut response)).unwrap();
+            let response = String::from_utf8(response).unwrap();
+            assert!(!response.contains("webkit"));
+            assert_eq!(negotiated.is_some(), response.contains("Sec-WebSocket-Extensions: permessage-deflate\r\n"))
+        }
+    }
+}
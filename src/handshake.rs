@@ -15,6 +15,7 @@ pub mod server;
 
 use bytes::BytesMut;
 use crate::extension::{Param, Extension};
+use sha1::{Digest, Sha1};
 use std::{fmt, io, str};
 
 pub use client::{Client, ServerResponse};
@@ -30,6 +31,27 @@ const MAX_NUM_HEADERS: usize = 32;
 // Some HTTP headers we need to check during parsing.
 const SEC_WEBSOCKET_EXTENSIONS: &str = "Sec-WebSocket-Extensions";
 const SEC_WEBSOCKET_PROTOCOL: &str = "Sec-WebSocket-Protocol";
+const SEC_WEBSOCKET_VERSION: &str = "Sec-WebSocket-Version";
+
+/// Compute the `Sec-WebSocket-Accept` value for a `Sec-WebSocket-Key`.
+///
+/// This is useful for frameworks which do their own HTTP handshake
+/// (cf. RFC 6455, section 4.2.2).
+pub fn accept_key(key: impl AsRef<[u8]>) -> String {
+    let mut buf = [0; 32];
+    let n = encode_accept_key(key.as_ref(), &mut buf);
+    buf[.. n].iter().map(|&b| char::from(b)).collect()
+}
+
+/// Write the base-64 encoded `Sec-WebSocket-Accept` value of `key` to `buf`.
+///
+/// Returns the number of bytes written.
+fn encode_accept_key(key: &[u8], buf: &mut [u8; 32]) -> usize {
+    let mut digest = Sha1::new();
+    digest.update(key);
+    digest.update(KEY);
+    base64::encode_config_slice(digest.finalize(), base64::STANDARD, buf)
+}
 
 /// Check a set of headers contains a specific one.
 fn expect_ascii_header(headers: &[httparse::Header], name: &str, ours: &str) -> Result<(), Error> {
@@ -41,9 +63,9 @@ fn expect_ascii_header(headers: &[httparse::Header], name: &str, ours: &str) ->
 
     headers.iter()
         .filter(|h| h.name.eq_ignore_ascii_case(name))
-        .fold(Ok(State::Init), |result, header| {
-            if let Ok(State::Match) = result {
-                return result
+        .try_fold(State::Init, |state, header| {
+            if let State::Match = state {
+                return Ok(state)
             }
             if str::from_utf8(header.value)?
                 .split(',')
@@ -62,6 +84,16 @@ fn expect_ascii_header(headers: &[httparse::Header], name: &str, ours: &str) ->
         })
 }
 
+/// Can the value be put into an HTTP header without breaking the message?
+fn is_valid_header_value(value: &str) -> bool {
+    value.bytes().all(|b| b == b'\t' || !b.is_ascii_control())
+}
+
+/// Is the value a valid HTTP token (cf. RFC 7230, section 3.2.6)?
+fn is_valid_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|b| b.is_ascii_graphic() && !b"\"(),/:;<=>?@[\\]{}".contains(&b))
+}
+
 /// Pick the first header with the given name and apply the given closure to it.
 fn with_first_header<'a, F, R>(headers: &[httparse::Header<'a>], name: &str, f: F) -> Result<R, Error>
 where
@@ -139,11 +171,13 @@ pub enum Error {
     HeaderNotFound(String),
     /// An HTTP header value was not expected.
     UnexpectedHeader(String),
+    /// A value we were asked to send contains characters not allowed in an HTTP header.
+    IllegalHeaderValue(String),
     /// The Sec-WebSocket-Accept header value did not match.
     InvalidSecWebSocketAccept,
     /// The server returned an extension we did not ask for.
     UnsolicitedExtension,
-    /// The server returned a protocol we did not ask for.
+    /// The server returned, or was asked to return, a protocol the client did not offer.
     UnsolicitedProtocol,
     /// An extension produced an error while encoding or decoding.
     Extension(crate::BoxedError),
@@ -166,6 +200,8 @@ impl fmt::Display for Error {
                 write!(f, "header {} not found", name),
             Error::UnexpectedHeader(name) =>
                 write!(f, "header {} had an unexpected value", name),
+            Error::IllegalHeaderValue(name) =>
+                write!(f, "header {} has an illegal value", name),
             Error::InvalidSecWebSocketAccept =>
                 f.write_str("websocket key mismatch"),
             Error::UnsolicitedExtension =>
@@ -193,6 +229,7 @@ impl std::error::Error for Error {
             | Error::InvalidRequestMethod
             | Error::HeaderNotFound(_)
             | Error::UnexpectedHeader(_)
+            | Error::IllegalHeaderValue(_)
             | Error::InvalidSecWebSocketAccept
             | Error::UnsolicitedExtension
             | Error::UnsolicitedProtocol
@@ -215,7 +252,7 @@ impl From<str::Utf8Error> for Error {
 
 #[cfg(test)]
 mod tests {
-    use super::expect_ascii_header;
+    use super::{accept_key, expect_ascii_header, is_valid_header_value, is_valid_token};
 
     #[test]
     fn header_match() {
@@ -242,4 +279,21 @@ mod tests {
         assert!(expect_ascii_header(headers, "baz", "???").is_err());
         assert!(expect_ascii_header(headers, "???", "x").is_err());
     }
+
+    #[test]
+    fn header_values() {
+        assert!(is_valid_header_value("example.com:8080"));
+        assert!(is_valid_header_value("a\tb c"));
+        assert!(!is_valid_header_value("example.com\r\nX-Injected: 1"));
+        assert!(is_valid_token("chat.v2"));
+        assert!(!is_valid_token(""));
+        assert!(!is_valid_token("chat, superchat"));
+        assert!(!is_valid_token("chat\r\n"))
+    }
+
+    #[test]
+    fn rfc_accept_key() {
+        // Example from RFC 6455, section 1.3.
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", accept_key("dGhlIHNhbXBsZSBub25jZQ=="))
+    }
 }
@@ -14,17 +14,18 @@ use bytes::{Buf, BytesMut};
 use crate::{Parsing, extension::Extension};
 use crate::connection::{self, Mode};
 use futures::prelude::*;
-use sha1::{Digest, Sha1};
-use std::{mem, str};
+use std::{mem, str, time::{Duration, Instant}};
 use super::{
     Error,
-    KEY,
     MAX_NUM_HEADERS,
     SEC_WEBSOCKET_EXTENSIONS,
     SEC_WEBSOCKET_PROTOCOL,
     append_extensions,
     configure_extensions,
+    encode_accept_key,
     expect_ascii_header,
+    is_valid_header_value,
+    is_valid_token,
     with_first_header
 };
 
@@ -45,12 +46,18 @@ pub struct Client<'a, T> {
     nonce: [u8; 32],
     /// The offset into the nonce buffer.
     nonce_offset: usize,
+    /// A fixed `Sec-WebSocket-Key` to use instead of a random nonce.
+    key: Option<[u8; 24]>,
     /// The protocols to include in the handshake.
     protocols: Vec<&'a str>,
+    /// Additional HTTP headers to include in the handshake.
+    headers: Vec<(&'a str, &'a str)>,
     /// The extensions the client wishes to include in the request.
     extensions: Vec<Box<dyn Extension + Send>>,
     /// Encoding/decoding buffer.
-    buffer: BytesMut
+    buffer: BytesMut,
+    /// Time between sending the request and receiving the response.
+    duration: Option<Duration>
 }
 
 impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
@@ -63,9 +70,12 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
             origin: None,
             nonce: [0; 32],
             nonce_offset: 0,
+            key: None,
             protocols: Vec::new(),
+            headers: Vec::new(),
             extensions: Vec::new(),
-            buffer: BytesMut::new()
+            buffer: BytesMut::new(),
+            duration: None
         }
     }
 
@@ -86,18 +96,55 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
         self
     }
 
+    /// Use the given `Sec-WebSocket-Key` instead of a randomly generated one.
+    ///
+    /// The key is the base-64 encoded form, as sent by a client. This is
+    /// meant for relays which forward a client's handshake upstream and want
+    /// to reuse the original key (see [`ClientRequest::key`]).
+    ///
+    /// A key which is not the base-64 encoding of 16 bytes is rejected with
+    /// [`Error::IllegalHeaderValue`].
+    ///
+    /// [`ClientRequest::key`]: crate::handshake::ClientRequest::key
+    pub fn set_key(&mut self, key: [u8; 24]) -> Result<&mut Self, Error> {
+        let mut nonce = [0; 18];
+        match base64::decode_config_slice(key, base64::STANDARD, &mut nonce) {
+            Ok(16) => {}
+            _ => return Err(Error::IllegalHeaderValue("Sec-WebSocket-Key".into()))
+        }
+        self.key = Some(key);
+        Ok(self)
+    }
+
     /// Add a protocol to be included in the handshake.
     pub fn add_protocol(&mut self, p: &'a str) -> &mut Self {
         self.protocols.push(p);
         self
     }
 
+    /// Add an HTTP header to be included in the handshake, e.g. for authentication.
+    ///
+    /// The name must be a valid token and the value must not contain control
+    /// characters, otherwise the handshake fails with
+    /// [`Error::IllegalHeaderValue`].
+    pub fn add_header(&mut self, name: &'a str, value: &'a str) -> &mut Self {
+        self.headers.push((name, value));
+        self
+    }
+
     /// Add an extension to be included in the handshake.
     pub fn add_extension(&mut self, e: Box<dyn Extension + Send>) -> &mut Self {
         self.extensions.push(e);
         self
     }
 
+    /// The names of the extensions negotiated with the server.
+    ///
+    /// Before the handshake has completed, no extensions are negotiated.
+    pub fn negotiated_extensions(&self) -> impl Iterator<Item = &str> {
+        self.extensions.iter().filter(|e| e.is_enabled()).map(|e| e.name())
+    }
+
     /// Get back all extensions.
     pub fn drain_extensions(&mut self) -> impl Iterator<Item = Box<dyn Extension + Send>> + '_ {
         self.extensions.drain(..)
@@ -106,20 +153,31 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
     /// Initiate client handshake request to server and get back the response.
     pub async fn handshake(&mut self) -> Result<ServerResponse, Error> {
         self.buffer.clear();
-        self.encode_request();
+        self.encode_request()?;
         self.socket.write_all(&self.buffer).await?;
         self.socket.flush().await?;
         self.buffer.clear();
+        self.duration = None;
+        let start = Instant::now();
 
         loop {
             crate::read(&mut self.socket, &mut self.buffer, BLOCK_SIZE).await?;
             if let Parsing::Done { value, offset } = self.decode_response()? {
+                self.duration = Some(start.elapsed());
                 self.buffer.advance(offset);
                 return Ok(value)
             }
         }
     }
 
+    /// The time it took the server to respond to our handshake request.
+    ///
+    /// Measured from after the request has been written until the complete
+    /// response has been received. `None` until [`Client::handshake`] succeeded.
+    pub fn handshake_duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
     /// Turn this handshake into a [`connection::Builder`].
     pub fn into_builder(mut self) -> connection::Builder<T> {
         let mut builder = connection::Builder::new(self.socket, Mode::Client);
@@ -134,9 +192,31 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
     }
 
     /// Encode the client handshake as a request, ready to be sent to the server.
-    fn encode_request(&mut self) {
-        let nonce: [u8; 16] = rand::random();
-        self.nonce_offset = base64::encode_config_slice(&nonce, base64::STANDARD, &mut self.nonce);
+    fn encode_request(&mut self) -> Result<(), Error> {
+        if self.resource.is_empty() || !self.resource.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(Error::IllegalHeaderValue("Request-Target".into()))
+        }
+        if !is_valid_header_value(self.host) {
+            return Err(Error::IllegalHeaderValue("Host".into()))
+        }
+        if !self.origin.map(is_valid_header_value).unwrap_or(true) {
+            return Err(Error::IllegalHeaderValue("Origin".into()))
+        }
+        if !self.protocols.iter().all(|p| is_valid_token(p)) {
+            return Err(Error::IllegalHeaderValue(SEC_WEBSOCKET_PROTOCOL.into()))
+        }
+        for (name, value) in &self.headers {
+            if !is_valid_token(name) || !is_valid_header_value(value) {
+                return Err(Error::IllegalHeaderValue((*name).into()))
+            }
+        }
+        if let Some(key) = &self.key {
+            self.nonce[.. key.len()].copy_from_slice(key);
+            self.nonce_offset = key.len()
+        } else {
+            let nonce: [u8; 16] = rand::random();
+            self.nonce_offset = base64::encode_config_slice(nonce, base64::STANDARD, &mut self.nonce)
+        }
         self.buffer.extend_from_slice(b"GET ");
         self.buffer.extend_from_slice(self.resource.as_bytes());
         self.buffer.extend_from_slice(b" HTTP/1.1");
@@ -157,8 +237,15 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
             }
             self.buffer.extend_from_slice(last.as_bytes())
         }
+        for (name, value) in &self.headers {
+            self.buffer.extend_from_slice(b"\r\n");
+            self.buffer.extend_from_slice(name.as_bytes());
+            self.buffer.extend_from_slice(b": ");
+            self.buffer.extend_from_slice(value.as_bytes())
+        }
         append_extensions(&self.extensions, &mut self.buffer);
-        self.buffer.extend_from_slice(b"\r\nSec-WebSocket-Version: 13\r\n\r\n")
+        self.buffer.extend_from_slice(b"\r\nSec-WebSocket-Version: 13\r\n\r\n");
+        Ok(())
     }
 
     /// Decode the server response to this client request.
@@ -195,12 +282,10 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
         expect_ascii_header(response.headers, "Connection", "upgrade")?;
 
         let nonce = &self.nonce[.. self.nonce_offset];
-        with_first_header(&response.headers, "Sec-WebSocket-Accept", |theirs| {
-            let mut digest = Sha1::new();
-            digest.update(nonce);
-            digest.update(KEY);
-            let ours = base64::encode(&digest.finalize());
-            if ours.as_bytes() != theirs {
+        with_first_header(response.headers, "Sec-WebSocket-Accept", |theirs| {
+            let mut buf = [0; 32];
+            let n = encode_accept_key(nonce, &mut buf);
+            if &buf[.. n] != theirs {
                 return Err(Error::InvalidSecWebSocketAccept)
             }
             Ok(())
@@ -254,3 +339,182 @@ pub enum ServerResponse {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use crate::{handshake::{Error, Server, server::Response}, test_util::duplex};
+    use futures::{executor::block_on, future, io::Cursor, prelude::*};
+    use super::{Client, ServerResponse};
+
+    #[test]
+    fn request_uses_given_key() {
+        let mut client = Client::new(Cursor::new(Vec::new()), "localhost", "/");
+        client.set_key(*b"dGhlIHNhbXBsZSBub25jZQ==").unwrap();
+        client.encode_request().unwrap();
+        let request = std::str::from_utf8(&client.buffer).unwrap();
+        assert!(request.contains("\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"))
+    }
+
+    #[test]
+    fn reject_invalid_key() {
+        let mut client = Client::new(Cursor::new(Vec::new()), "localhost", "/");
+        for key in &[*b"dGhlIHNhbXBsZSBub25j\r\nX:", *b"dGhlIHNhbXBsZSBub25jZQ=x", *b"dGhlIHNhbXBsZSBub25jZQAA"] {
+            assert!(matches!(client.set_key(*key), Err(Error::IllegalHeaderValue(h)) if h == "Sec-WebSocket-Key"))
+        }
+        client.encode_request().unwrap();
+        let request = std::str::from_utf8(&client.buffer).unwrap();
+        assert!(!request.contains("dGhlIHNhbXBsZSBub25j"))
+    }
+
+    #[test]
+    fn reject_illegal_header_values() {
+        let mut client = Client::new(Cursor::new(Vec::new()), "localhost\r\nX-Injected: 1", "/");
+        assert!(matches!(client.encode_request(), Err(Error::IllegalHeaderValue(h)) if h == "Host"));
+
+        let mut client = Client::new(Cursor::new(Vec::new()), "localhost", "/a b");
+        assert!(matches!(client.encode_request(), Err(Error::IllegalHeaderValue(_))));
+
+        let mut client = Client::new(Cursor::new(Vec::new()), "localhost", "/");
+        client.set_origin("http://example.com\n");
+        assert!(matches!(client.encode_request(), Err(Error::IllegalHeaderValue(h)) if h == "Origin"));
+
+        let mut client = Client::new(Cursor::new(Vec::new()), "localhost", "/");
+        client.add_protocol("chat,superchat");
+        assert!(matches! {
+            client.encode_request(),
+            Err(Error::IllegalHeaderValue(h)) if h == "Sec-WebSocket-Protocol"
+        })
+    }
+
+    #[test]
+    fn records_handshake_duration() {
+        let (a, b) = duplex();
+        let mut client = Client::new(a, "localhost", "/");
+        let mut server = Server::new(b);
+        assert!(client.handshake_duration().is_none());
+        block_on(async {
+            let respond = async {
+                let key = server.receive_request().await.unwrap().into_key();
+                server.send_response(&Response::Accept { key: &key, protocol: None }).await.unwrap()
+            };
+            let (response, ()) = future::join(client.handshake(), respond).await;
+            assert!(matches!(response, Ok(ServerResponse::Accepted { .. })))
+        });
+        assert!(client.handshake_duration().is_some())
+    }
+
+    /// Run a client handshake against a server sending the given response.
+    fn handshake_error(response: &[u8]) -> Error {
+        let (a, mut b) = duplex();
+        let mut client = Client::new(a, "localhost", "/");
+        block_on(async {
+            b.write_all(response).await.unwrap();
+            let e = client.handshake().await.unwrap_err();
+            assert!(client.handshake_duration().is_none());
+            e
+        })
+    }
+
+    #[test]
+    fn fail_on_malformed_response() {
+        assert!(matches!(handshake_error(b"garbage\r\n\r\n"), Error::Http(_)));
+        assert!(matches! {
+            handshake_error(b"HTTP/1.0 101 Switching Protocols\r\n\r\n"),
+            Error::UnsupportedHttpVersion
+        });
+        assert!(matches! {
+            handshake_error(b"HTTP/1.1 101 Switching Protocols\r\n\
+                Upgrade: websocket\r\n\
+                Connection: Upgrade\r\n\
+                Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"),
+            Error::InvalidSecWebSocketAccept
+        })
+    }
+
+    #[test]
+    fn server_selects_one_protocol() {
+        let (a, b) = duplex();
+        let mut client = Client::new(a, "localhost", "/");
+        client.add_protocol("chat").add_protocol("superchat");
+        let mut server = Server::new(b);
+        server.add_protocol("superchat");
+        block_on(async {
+            let (response, _) = future::join(client.handshake(), async {
+                let request = server.receive_request().await.unwrap();
+                let protocol = request.protocols().next();
+                assert_eq!(Some("superchat"), protocol);
+                let response = Response::Accept { key: request.key(), protocol };
+                server.send_response(&response).await.unwrap()
+            }).await;
+            assert!(matches! {
+                response.unwrap(),
+                ServerResponse::Accepted { protocol: Some(p) } if p == "superchat"
+            })
+        })
+    }
+
+    #[test]
+    fn handshake_round_trip() {
+        let (a, b) = duplex();
+        let mut client = Client::new(a, "localhost", "/chat");
+        let mut server = Server::new(b);
+        block_on(async {
+            let (response, path) = future::join(client.handshake(), async {
+                let request = server.receive_request().await.unwrap();
+                let response = Response::Accept { key: request.key(), protocol: None };
+                server.send_response(&response).await.unwrap();
+                request.path().to_string()
+            }).await;
+            assert!(matches!(response, Ok(ServerResponse::Accepted { protocol: None })));
+            assert_eq!("/chat", path);
+
+            let (mut client_tx, mut client_rx) = client.into_builder().finish();
+            let (mut server_tx, mut server_rx) = server.into_builder().finish();
+            let mut message = Vec::new();
+            client_tx.send_text("ping").await.unwrap();
+            server_rx.receive_data(&mut message).await.unwrap();
+            assert_eq!(b"ping", &message[..]);
+            message.clear();
+            server_tx.send_text("pong").await.unwrap();
+            client_rx.receive_data(&mut message).await.unwrap();
+            assert_eq!(b"pong", &message[..])
+        })
+    }
+
+    #[test]
+    fn origin_round_trip() {
+        let (a, b) = duplex();
+        let mut client = Client::new(a, "localhost", "/");
+        client.set_origin("https://example.com");
+        client.encode_request().unwrap();
+        let header = b"\r\nOrigin: https://example.com\r\n";
+        assert!(client.buffer.windows(header.len()).any(|w| w == header));
+        client.buffer.clear();
+
+        let mut server = Server::new(b);
+        block_on(async {
+            let (_, origin) = future::join(client.handshake(), async {
+                let request = server.receive_request().await.unwrap();
+                let response = Response::Accept { key: request.key(), protocol: None };
+                server.send_response(&response).await.unwrap();
+                request.origin().map(String::from)
+            }).await;
+            assert_eq!(Some("https://example.com"), origin.as_deref())
+        })
+    }
+
+    #[test]
+    fn additional_headers() {
+        let mut client = Client::new(Cursor::new(Vec::new()), "localhost", "/");
+        client.add_header("Authorization", "Bearer 123").add_header("X-Trace", "abc");
+        client.encode_request().unwrap();
+        let header = b"\r\nAuthorization: Bearer 123\r\nX-Trace: abc\r\n";
+        assert!(client.buffer.windows(header.len()).any(|w| w == header));
+
+        for &(name, value) in &[("X Trace", "abc"), ("X-Trace", "abc\r\nX-Injected: 1")] {
+            let mut client = Client::new(Cursor::new(Vec::new()), "localhost", "/");
+            client.add_header(name, value);
+            assert!(matches!(client.encode_request(), Err(Error::IllegalHeaderValue(h)) if h == name))
+        }
+    }
+}
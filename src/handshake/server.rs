@@ -14,17 +14,19 @@ use bytes::{Buf, BytesMut};
 use crate::{Parsing, extension::Extension};
 use crate::connection::{self, Mode};
 use futures::prelude::*;
-use sha1::{Digest, Sha1};
 use std::{mem, str};
 use super::{
     Error,
-    KEY,
     MAX_NUM_HEADERS,
     SEC_WEBSOCKET_EXTENSIONS,
     SEC_WEBSOCKET_PROTOCOL,
+    SEC_WEBSOCKET_VERSION,
     append_extensions,
     configure_extensions,
+    encode_accept_key,
     expect_ascii_header,
+    is_valid_header_value,
+    is_valid_token,
     with_first_header
 };
 
@@ -37,6 +39,16 @@ pub struct Server<'a, T> {
     socket: T,
     /// Protocols the server supports.
     protocols: Vec<&'a str>,
+    /// Order offered protocols by our preference instead of the client's.
+    own_protocol_order: bool,
+    /// Origins allowed to connect (any origin if empty).
+    allowed_origins: Vec<&'a str>,
+    /// Maximum size of a request body we are willing to discard.
+    max_body_size: usize,
+    /// The response to send after a malformed request.
+    error_response: Option<Response<'static>>,
+    /// Protocols of the last request we may select in our response.
+    offered_protocols: Vec<&'a str>,
     /// Extensions the server supports.
     extensions: Vec<Box<dyn Extension + Send>>,
     /// Encoding/decoding buffer.
@@ -49,6 +61,11 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
         Server {
             socket,
             protocols: Vec::new(),
+            own_protocol_order: false,
+            allowed_origins: Vec::new(),
+            max_body_size: 0,
+            error_response: None,
+            offered_protocols: Vec::new(),
             extensions: Vec::new(),
             buffer: BytesMut::new()
         }
@@ -71,36 +88,117 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
         self
     }
 
+    /// Prefer protocols in the order they have been added.
+    ///
+    /// By default, [`ClientRequest::protocols`] lists the supported protocols
+    /// in the order the client offered them. If enabled, they are listed in
+    /// the order they have been added with [`Server::add_protocol`] instead.
+    pub fn set_own_protocol_order(&mut self, enabled: bool) -> &mut Self {
+        self.own_protocol_order = enabled;
+        self
+    }
+
+    /// Add an origin which is allowed to connect.
+    ///
+    /// Once an origin has been added, requests without an `Origin` header or
+    /// with an origin not added here are rejected with `403 Forbidden`.
+    /// Origins are compared exactly, e.g. `https://example.com`.
+    pub fn add_allowed_origin(&mut self, origin: &'a str) -> &mut Self {
+        self.allowed_origins.push(origin);
+        self
+    }
+
+    /// Set the maximum size of a request body to accept.
+    ///
+    /// Handshake requests must not have a body, so by default requests with a
+    /// non-zero `Content-Length` are rejected. With a maximum set, a body of up
+    /// to `max` bytes is read and discarded instead. Requests using a
+    /// `Transfer-Encoding` are always rejected.
+    pub fn set_max_body_size(&mut self, max: usize) -> &mut Self {
+        self.max_body_size = max;
+        self
+    }
+
     /// Add an extension the server supports.
     pub fn add_extension(&mut self, e: Box<dyn Extension + Send>) -> &mut Self {
         self.extensions.push(e);
         self
     }
 
+    /// The names of the extensions negotiated with the client.
+    ///
+    /// Before the handshake has completed, no extensions are negotiated.
+    pub fn negotiated_extensions(&self) -> impl Iterator<Item = &str> {
+        self.extensions.iter().filter(|e| e.is_enabled()).map(|e| e.name())
+    }
+
     /// Get back all extensions.
     pub fn drain_extensions(&mut self) -> impl Iterator<Item = Box<dyn Extension + Send>> + '_ {
         self.extensions.drain(..)
     }
 
     /// Await an incoming client handshake request.
+    ///
+    /// If the request is malformed, a `400 Bad Request` response is staged
+    /// which can be retrieved with [`Server::error_response`] and sent to
+    /// the client before closing the connection. For an unsupported
+    /// websocket version this is a `426 Upgrade Required` response which
+    /// tells the client to use version 13, for an origin which is not
+    /// allowed a `403 Forbidden` response.
     pub async fn receive_request(&mut self) -> Result<ClientRequest<'a>, Error> {
         self.buffer.clear();
+        self.error_response = None;
+        self.offered_protocols.clear();
         loop {
             crate::read(&mut self.socket, &mut self.buffer, BLOCK_SIZE).await?;
-            if let Parsing::Done { value, offset } = self.decode_request()? {
-                self.buffer.advance(offset);
-                return Ok(value)
+            match self.decode_request() {
+                Ok(Parsing::Done { value, offset }) => {
+                    self.buffer.advance(offset);
+                    return Ok(value)
+                }
+                Ok(Parsing::NeedMore(())) => {}
+                Err(e) => {
+                    self.error_response = Some(error_response(&e));
+                    return Err(e)
+                }
             }
         }
     }
 
+    /// The response to send after [`Server::receive_request`] failed on a
+    /// malformed request, if any.
+    pub fn error_response(&self) -> Option<Response<'static>> {
+        self.error_response.clone()
+    }
+
     /// Respond to the client.
+    ///
+    /// Any bytes the client sent after its request are kept and will be
+    /// decoded as websocket frames once the connection has been established.
+    ///
+    /// The protocol of an `Accept` response must be one of the request's
+    /// [`ClientRequest::protocols`], otherwise [`Error::UnsolicitedProtocol`]
+    /// is returned.
     pub async fn send_response(&mut self, r: &Response<'_>) -> Result<(), Error> {
-        self.buffer.clear();
+        match r {
+            Response::Accept { protocol: Some(p), .. } if !is_valid_token(p) =>
+                return Err(Error::IllegalHeaderValue(SEC_WEBSOCKET_PROTOCOL.into())),
+            Response::Accept { protocol: Some(p), .. } if !self.offered_protocols.contains(p) =>
+                return Err(Error::UnsolicitedProtocol),
+            Response::RejectWith { headers, .. } =>
+                for (name, value) in headers.iter() {
+                    if !is_valid_token(name) || !is_valid_header_value(value) {
+                        return Err(Error::IllegalHeaderValue((*name).into()))
+                    }
+                }
+            _ => {}
+        }
+        let received = self.buffer.split();
         self.encode_response(r);
         self.socket.write_all(&self.buffer).await?;
         self.socket.flush().await?;
         self.buffer.clear();
+        self.buffer.unsplit(received);
         Ok(())
     }
 
@@ -135,15 +233,39 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
             return Err(Error::UnsupportedHttpVersion)
         }
 
+        if request.headers.iter().any(|h| h.name.eq_ignore_ascii_case("Transfer-Encoding")) {
+            return Err(Error::UnexpectedHeader("Transfer-Encoding".into()))
+        }
+        let body_len = match request.headers.iter().find(|h| h.name.eq_ignore_ascii_case("Content-Length")) {
+            Some(h) => match std::str::from_utf8(h.value)?.trim().parse::<usize>() {
+                Ok(n) if n <= self.max_body_size => n,
+                _ => return Err(Error::UnexpectedHeader("Content-Length".into()))
+            }
+            None => 0
+        };
+        if self.buffer.len() < offset + body_len {
+            return Ok(Parsing::NeedMore(()))
+        }
+        let offset = offset + body_len; // skip over the body
+
         // TODO: Host Validation
-        with_first_header(&request.headers, "Host", |_h| Ok(()))?;
+        with_first_header(request.headers, "Host", |_h| Ok(()))?;
 
         expect_ascii_header(request.headers, "Upgrade", "websocket")?;
         expect_ascii_header(request.headers, "Connection", "upgrade")?;
-        expect_ascii_header(request.headers, "Sec-WebSocket-Version", "13")?;
+        expect_ascii_header(request.headers, SEC_WEBSOCKET_VERSION, "13")?;
 
-        let ws_key = with_first_header(&request.headers, "Sec-WebSocket-Key", |k| {
-            Ok(Vec::from(k))
+        let ws_key = with_first_header(request.headers, "Sec-WebSocket-Key", |k| {
+            // The key must be a base64-encoded 16-byte value (RFC 6455, 4.2.1).
+            // Longer keys must not reach the decoder, which would overflow `nonce`.
+            if k.len() != 24 {
+                return Err(Error::UnexpectedHeader("Sec-WebSocket-Key".into()))
+            }
+            let mut nonce = [0; 18];
+            match base64::decode_config_slice(k, base64::STANDARD, &mut nonce) {
+                Ok(16) => Ok(Vec::from(k)),
+                _ => Err(Error::UnexpectedHeader("Sec-WebSocket-Key".into()))
+            }
         })?;
 
         for h in request.headers.iter()
@@ -153,21 +275,46 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
         }
 
         let mut protocols = Vec::new();
-        for p in request.headers.iter()
+        for h in request.headers.iter()
             .filter(|h| h.name.eq_ignore_ascii_case(SEC_WEBSOCKET_PROTOCOL))
         {
-            if let Some(&p) = self.protocols.iter().find(|x| x.as_bytes() == p.value) {
-                protocols.push(p)
+            for p in std::str::from_utf8(h.value)?.split(',').map(str::trim) {
+                if let Some(&p) = self.protocols.iter().find(|x| **x == p) {
+                    if !protocols.contains(&p) {
+                        protocols.push(p)
+                    }
+                }
             }
         }
+        if self.own_protocol_order {
+            protocols.sort_by_key(|p| self.protocols.iter().position(|x| x == p))
+        }
 
         let mut path = String::new();
         if let Some(val) = request.path {
             path.push_str(val)
         }
 
+        let origin = match request.headers.iter().find(|h| h.name.eq_ignore_ascii_case("Origin")) {
+            Some(h) => Some(std::str::from_utf8(h.value)?.to_string()),
+            None => None
+        };
+        if !self.allowed_origins.is_empty() {
+            match &origin {
+                Some(o) if self.allowed_origins.contains(&o.as_str()) => {}
+                Some(_) => return Err(Error::UnexpectedHeader("Origin".into())),
+                None => return Err(Error::HeaderNotFound("Origin".into()))
+            }
+        }
+
+        let headers = request.headers.iter()
+            .map(|h| (h.name.to_ascii_lowercase(), Vec::from(h.value)))
+            .collect();
+
+        self.offered_protocols = protocols.clone();
+
         Ok(Parsing::Done {
-            value: ClientRequest { ws_key, protocols, path }, offset,
+            value: ClientRequest { ws_key, protocols, path, origin, headers }, offset,
         })
     }
 
@@ -176,14 +323,8 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
         match response {
             Response::Accept { key, protocol } => {
                 let mut key_buf = [0; 32];
-                let accept_value = {
-                    let mut digest = Sha1::new();
-                    digest.update(key);
-                    digest.update(KEY);
-                    let d = digest.finalize();
-                    let n = base64::encode_config_slice(&d, base64::STANDARD, &mut key_buf);
-                    &key_buf[.. n]
-                };
+                let n = encode_accept_key(key, &mut key_buf);
+                let accept_value = &key_buf[.. n];
                 self.buffer.extend_from_slice(b"HTTP/1.1 101 Switching Protocols");
                 self.buffer.extend_from_slice(b"\r\nServer: soket-");
                 self.buffer.extend_from_slice(SOKET_VERSION.as_bytes());
@@ -198,20 +339,38 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
                 self.buffer.extend_from_slice(b"\r\n\r\n")
             }
             Response::Reject { status_code } => {
-                self.buffer.extend_from_slice(b"HTTP/1.1 ");
-                let (_, s, reason) =
-                    if let Ok(i) = STATUSCODES.binary_search_by_key(status_code, |(n, _, _)| *n) {
-                        STATUSCODES[i]
-                    } else {
-                        (500, "500", "Internal Server Error")
-                    };
-                self.buffer.extend_from_slice(s.as_bytes());
-                self.buffer.extend_from_slice(b" ");
-                self.buffer.extend_from_slice(reason.as_bytes());
+                self.encode_status_line(*status_code);
                 self.buffer.extend_from_slice(b"\r\n\r\n")
             }
+            Response::RejectWith { status_code, headers, body } => {
+                self.encode_status_line(*status_code);
+                for (name, value) in headers.iter() {
+                    self.buffer.extend_from_slice(b"\r\n");
+                    self.buffer.extend_from_slice(name.as_bytes());
+                    self.buffer.extend_from_slice(b": ");
+                    self.buffer.extend_from_slice(value.as_bytes())
+                }
+                self.buffer.extend_from_slice(b"\r\nContent-Length: ");
+                self.buffer.extend_from_slice(body.len().to_string().as_bytes());
+                self.buffer.extend_from_slice(b"\r\n\r\n");
+                self.buffer.extend_from_slice(body)
+            }
         }
     }
+
+    // Encode the status line of a rejection, without line terminator.
+    fn encode_status_line(&mut self, status_code: u16) {
+        self.buffer.extend_from_slice(b"HTTP/1.1 ");
+        let (_, s, reason) =
+            if let Ok(i) = STATUSCODES.binary_search_by_key(&status_code, |(n, _, _)| *n) {
+                STATUSCODES[i]
+            } else {
+                (500, "500", "Internal Server Error")
+            };
+        self.buffer.extend_from_slice(s.as_bytes());
+        self.buffer.extend_from_slice(b" ");
+        self.buffer.extend_from_slice(reason.as_bytes())
+    }
 }
 
 /// Handshake request received from the client.
@@ -220,6 +379,8 @@ pub struct ClientRequest<'a> {
     ws_key: Vec<u8>,
     protocols: Vec<&'a str>,
     path: String,
+    origin: Option<String>,
+    headers: Vec<(String, Vec<u8>)>
 }
 
 impl<'a> ClientRequest<'a> {
@@ -228,11 +389,14 @@ impl<'a> ClientRequest<'a> {
         &self.ws_key
     }
 
+    /// Turn this request into the nonce.
     pub fn into_key(self) -> Vec<u8> {
         self.ws_key
     }
 
-    /// The protocols the client is proposing.
+    /// The protocols the client is proposing and the server supports.
+    ///
+    /// See [`Server::set_own_protocol_order`] for how they are ordered.
     pub fn protocols(&self) -> impl Iterator<Item = &str> {
         self.protocols.iter().cloned()
     }
@@ -241,10 +405,29 @@ impl<'a> ClientRequest<'a> {
     pub fn path(&self) -> &str {
         &self.path
     }
+
+    /// All headers of the request in the order they were received.
+    ///
+    /// Header names are converted to lowercase.
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.headers.iter().map(|(n, v)| (n.as_str(), v.as_slice()))
+    }
+
+    /// The value of the first header with the given name, if any.
+    ///
+    /// Header names are compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v)
+    }
+
+    /// The value of the client's `Origin` header, if any.
+    pub fn origin(&self) -> Option<&str> {
+        self.origin.as_deref()
+    }
 }
 
 /// Handshake response the server sends back to the client.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Response<'a> {
     /// The server accepts the handshake request.
     Accept {
@@ -254,6 +437,29 @@ pub enum Response<'a> {
     /// The server rejects the handshake request.
     Reject {
         status_code: u16
+    },
+    /// The server rejects the handshake request with extra headers and a body.
+    ///
+    /// A `Content-Length` header is added automatically.
+    RejectWith {
+        status_code: u16,
+        headers: &'a [(&'a str, &'a str)],
+        body: &'a [u8]
+    }
+}
+
+/// The response to send after receiving a malformed request.
+fn error_response(e: &Error) -> Response<'static> {
+    match e {
+        Error::HeaderNotFound(h) | Error::UnexpectedHeader(h) if h == SEC_WEBSOCKET_VERSION =>
+            Response::RejectWith {
+                status_code: 426,
+                headers: &[(SEC_WEBSOCKET_VERSION, "13")],
+                body: &[]
+            },
+        Error::HeaderNotFound(h) | Error::UnexpectedHeader(h) if h == "Origin" =>
+            Response::Reject { status_code: 403 },
+        _ => Response::Reject { status_code: 400 }
     }
 }
 
@@ -321,3 +527,307 @@ const STATUSCODES: &[(u16, &str, &str)] = &[
     (511, "511", "Network Authentication Required")
 ];
 
+
+#[cfg(test)]
+mod tests {
+    use crate::handshake::Error;
+    use futures::{executor::block_on, io::Cursor};
+    use super::{Response, Server};
+
+    const REQUEST: &[u8] =
+        b"GET /chat HTTP/1.1\r\n\
+          Host: server.example.com\r\n\
+          Upgrade: websocket\r\n\
+          Connection: Upgrade\r\n\
+          Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+          Sec-WebSocket-Version: 13\r\n\r\n";
+
+    #[test]
+    fn request_exposes_key() {
+        let mut server = Server::new(Cursor::new(REQUEST.to_vec()));
+        let request = block_on(server.receive_request()).unwrap();
+        assert_eq!(b"dGhlIHNhbXBsZSBub25jZQ==", request.key());
+        assert_eq!("/chat", request.path())
+    }
+
+    #[test]
+    fn keep_frames_sent_before_response() {
+        // A masked text frame "Hello" from RFC 6455, section 5.7, directly after the request.
+        let frame = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let mut input = REQUEST.to_vec();
+        input.extend_from_slice(&frame);
+        let mut server = Server::new(Cursor::new(input));
+        block_on(async {
+            let key = server.receive_request().await.unwrap().into_key();
+            server.send_response(&Response::Accept { key: &key, protocol: None }).await.unwrap();
+            let (_, mut receiver) = server.into_builder().finish();
+            let mut message = Vec::new();
+            assert!(receiver.receive_data(&mut message).await.unwrap().is_text());
+            assert_eq!(b"Hello", &message[..])
+        })
+    }
+
+    #[test]
+    fn protocol_preference() {
+        let mut request = REQUEST[.. REQUEST.len() - 2].to_vec();
+        request.extend_from_slice(b"Sec-WebSocket-Protocol: a, b\r\nSec-WebSocket-Protocol: c\r\n\r\n");
+
+        let mut server = Server::new(Cursor::new(request.clone()));
+        server.add_protocol("c").add_protocol("b").add_protocol("a");
+        let r = block_on(server.receive_request()).unwrap();
+        assert_eq!(vec!["a", "b", "c"], r.protocols().collect::<Vec<_>>());
+
+        let mut server = Server::new(Cursor::new(request));
+        server.add_protocol("b").add_protocol("a").set_own_protocol_order(true);
+        let r = block_on(server.receive_request()).unwrap();
+        assert_eq!(vec!["b", "a"], r.protocols().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn tolerate_whitespace_and_bare_lf() {
+        let request =
+            b"GET /chat HTTP/1.1\r\n\
+              Host:server.example.com\n\
+              Upgrade: \t websocket \t\r\n\
+              Connection:  keep-alive ,  Upgrade  \r\n\
+              Sec-WebSocket-Key:   dGhlIHNhbXBsZSBub25jZQ==   \r\n\
+              Sec-WebSocket-Version: 13 \n\r\n";
+        let mut server = Server::new(Cursor::new(request.to_vec()));
+        let request = block_on(server.receive_request()).unwrap();
+        assert_eq!(b"dGhlIHNhbXBsZSBub25jZQ==", request.key())
+    }
+
+    #[test]
+    fn reject_obsolete_line_folding() {
+        let mut request = REQUEST[.. REQUEST.len() - 2].to_vec();
+        request.extend_from_slice(b"X-Folded: a\r\n b\r\n\r\n");
+        let mut server = Server::new(Cursor::new(request));
+        assert!(matches!(block_on(server.receive_request()), Err(Error::Http(_))))
+    }
+
+    #[test]
+    fn reject_with_headers_and_body() {
+        let mut server = Server::new(Cursor::new(Vec::new()));
+        let response = Response::RejectWith {
+            status_code: 429,
+            headers: &[("Retry-After", "120")],
+            body: b"slow down"
+        };
+        block_on(server.send_response(&response)).unwrap();
+        let output = server.into_inner().into_inner();
+        assert_eq! {
+            &b"HTTP/1.1 429 Too Many Requests\r\n\
+               Retry-After: 120\r\n\
+               Content-Length: 9\r\n\r\n\
+               slow down"[..],
+            &output[..]
+        };
+
+        let mut server = Server::new(Cursor::new(Vec::new()));
+        let response = Response::RejectWith {
+            status_code: 503,
+            headers: &[("Retry-After", "1\r\nX-Injected: 1")],
+            body: &[]
+        };
+        assert!(matches!(block_on(server.send_response(&response)), Err(Error::IllegalHeaderValue(_))))
+    }
+
+    #[test]
+    fn reject_unauthorized_request() {
+        let mut server = Server::new(Cursor::new(REQUEST.to_vec()));
+        block_on(async {
+            server.receive_request().await.unwrap();
+            let response = Response::RejectWith { status_code: 403, headers: &[], body: b"forbidden" };
+            server.send_response(&response).await.unwrap()
+        });
+        let output = server.into_inner().into_inner();
+        let response = &output[REQUEST.len() ..];
+        assert!(response.starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+        assert!(response.ends_with(b"\r\n\r\nforbidden"))
+    }
+
+    #[test]
+    fn select_one_of_several_protocols() {
+        let mut request = REQUEST[.. REQUEST.len() - 2].to_vec();
+        request.extend_from_slice(b"Sec-WebSocket-Protocol: chat ,superchat  ,  other\r\n\r\n");
+        let mut server = Server::new(Cursor::new(request));
+        server.add_protocol("superchat").add_protocol("chat");
+        let r = block_on(server.receive_request()).unwrap();
+        assert_eq!(vec!["chat", "superchat"], r.protocols().collect::<Vec<_>>());
+
+        let response = Response::Accept { key: r.key(), protocol: Some("chat, superchat") };
+        assert!(matches!(block_on(server.send_response(&response)), Err(Error::IllegalHeaderValue(_))))
+    }
+
+    #[test]
+    fn request_body() {
+        let mut request = REQUEST[.. REQUEST.len() - 2].to_vec();
+        request.extend_from_slice(b"Content-Length: 10\r\n\r\n0123456789");
+        request.extend_from_slice(&[0x81, 0x82, 0, 0, 0, 0, b'h', b'i']);
+
+        let mut server = Server::new(Cursor::new(request.clone()));
+        assert!(matches! {
+            block_on(server.receive_request()),
+            Err(Error::UnexpectedHeader(h)) if h == "Content-Length"
+        });
+
+        let mut server = Server::new(Cursor::new(request));
+        server.set_max_body_size(10);
+        block_on(async {
+            let key = server.receive_request().await.unwrap().into_key();
+            server.send_response(&Response::Accept { key: &key, protocol: None }).await.unwrap();
+            let (_, mut receiver) = server.into_builder().finish();
+            let mut message = Vec::new();
+            receiver.receive_data(&mut message).await.unwrap();
+            assert_eq!(b"hi", &message[..])
+        })
+    }
+
+    #[test]
+    fn bad_request() {
+        let mut server = Server::new(Cursor::new(b"GET /chat\r\n\r\n".to_vec()));
+        block_on(async {
+            assert!(matches!(server.receive_request().await, Err(Error::Http(_))));
+            let response = server.error_response().unwrap();
+            assert!(matches!(response, Response::Reject { status_code: 400 }));
+            server.send_response(&response).await.unwrap()
+        });
+        let output = server.into_inner().into_inner();
+        assert!(output.ends_with(b"HTTP/1.1 400 Bad Request\r\n\r\n"));
+
+        let mut server = Server::new(Cursor::new(REQUEST.to_vec()));
+        block_on(server.receive_request()).unwrap();
+        assert!(server.error_response().is_none())
+    }
+
+    #[test]
+    fn omit_extensions_header_if_none_negotiated() {
+        use crate::test_util::Stub;
+
+        let mut request = REQUEST[.. REQUEST.len() - 2].to_vec();
+        request.extend_from_slice(b"Sec-WebSocket-Extensions: declining; x=1\r\n\r\n");
+        let request_len = request.len();
+        let mut server = Server::new(Cursor::new(request));
+        server.add_extension(Box::new(Stub::new("declining").disabled()));
+        block_on(async {
+            let key = server.receive_request().await.unwrap().into_key();
+            server.send_response(&Response::Accept { key: &key, protocol: None }).await.unwrap()
+        });
+        let output = server.into_inner().into_inner();
+        let response = &output[request_len ..];
+        assert!(response.starts_with(b"HTTP/1.1 101 "));
+        assert!(!response.windows(b"Extensions".len()).any(|w| w == b"Extensions"))
+    }
+
+    #[test]
+    fn request_headers() {
+        let mut request = REQUEST[.. REQUEST.len() - 2].to_vec();
+        request.extend_from_slice(b"Authorization: Bearer abc\r\nCookie: a=1\r\ncookie: b=2\r\n\r\n");
+        let mut server = Server::new(Cursor::new(request));
+        let request = block_on(server.receive_request()).unwrap();
+        assert_eq!(Some(("host", &b"server.example.com"[..])), request.headers().next());
+        assert_eq!(Some(&b"Bearer abc"[..]), request.header("authorization"));
+        assert_eq!(Some(&b"a=1"[..]), request.header("COOKIE"));
+        assert_eq!(None, request.header("x-missing"));
+        let cookies: Vec<_> = request.headers().filter(|(n, _)| *n == "cookie").map(|(_, v)| v).collect();
+        assert_eq!(vec![&b"a=1"[..], &b"b=2"[..]], cookies)
+    }
+
+    #[test]
+    fn allowed_origins() {
+        let with_origin = |origin: &str| {
+            let mut request = REQUEST[.. REQUEST.len() - 2].to_vec();
+            request.extend_from_slice(format!("Origin: {}\r\n\r\n", origin).as_bytes());
+            request
+        };
+
+        let mut server = Server::new(Cursor::new(with_origin("https://example.com")));
+        server.add_allowed_origin("https://example.org").add_allowed_origin("https://example.com");
+        let request = block_on(server.receive_request()).unwrap();
+        assert_eq!(Some("https://example.com"), request.origin());
+
+        for input in &[with_origin("https://Example.com"), with_origin("https://example.com:443"), REQUEST.to_vec()] {
+            let mut server = Server::new(Cursor::new(input.clone()));
+            server.add_allowed_origin("https://example.com");
+            match block_on(server.receive_request()) {
+                Err(Error::UnexpectedHeader(h)) | Err(Error::HeaderNotFound(h)) => assert_eq!("Origin", h),
+                other => panic!("unexpected result: {:?}", other)
+            }
+            assert!(matches!(server.error_response(), Some(Response::Reject { status_code: 403 })))
+        }
+
+        let mut server = Server::new(Cursor::new(REQUEST.to_vec()));
+        assert!(block_on(server.receive_request()).is_ok())
+    }
+
+    #[test]
+    fn reject_overlong_key() {
+        let key = b"dGhlIHNhbXBsZSBub25jZQ==";
+        let request = REQUEST.to_vec();
+        let at = request.windows(key.len()).position(|w| w == key).unwrap();
+        for long_key in &[&b"dGhlIHNhbXBsZSBub25jZQ==dGhlIHNhbXBsZSBub25jZQ=="[..], b"dGhlIHNhbXBsZSBub25jZQ==AAAA"] {
+            let mut request = request.clone();
+            request.splice(at .. at + key.len(), long_key.iter().copied());
+            let mut server = Server::new(Cursor::new(request));
+            match block_on(server.receive_request()) {
+                Err(Error::UnexpectedHeader(h)) => assert_eq!("Sec-WebSocket-Key", h),
+                other => panic!("unexpected result: {:?}", other)
+            }
+            assert!(matches!(server.error_response(), Some(Response::Reject { status_code: 400 })))
+        }
+    }
+
+    #[test]
+    fn reject_unoffered_protocol() {
+        let mut request = REQUEST[.. REQUEST.len() - 2].to_vec();
+        request.extend_from_slice(b"Sec-WebSocket-Protocol: chat\r\n\r\n");
+        let mut server = Server::new(Cursor::new(request));
+        server.add_protocol("chat").add_protocol("superchat");
+        let r = block_on(server.receive_request()).unwrap();
+        let response = Response::Accept { key: r.key(), protocol: Some("superchat") };
+        assert!(matches!(block_on(server.send_response(&response)), Err(Error::UnsolicitedProtocol)));
+        let response = Response::Accept { key: r.key(), protocol: Some("chat") };
+        assert!(block_on(server.send_response(&response)).is_ok())
+    }
+
+    #[test]
+    fn required_headers() {
+        fn receive(request: &[u8]) -> (Error, Response<'static>) {
+            let mut server = Server::new(Cursor::new(request.to_vec()));
+            let e = block_on(server.receive_request()).unwrap_err();
+            (e, server.error_response().unwrap())
+        }
+        let without = |header: &str| -> Vec<u8> {
+            let request = std::str::from_utf8(REQUEST).unwrap();
+            request.lines().filter(|l| !l.starts_with(header)).map(|l| format!("{}\r\n", l)).collect::<String>().into_bytes()
+        };
+        let replace = |header: &str, value: &str| -> Vec<u8> {
+            let mut request = without(header);
+            request.truncate(request.len() - 2);
+            request.extend_from_slice(format!("{}: {}\r\n\r\n", header, value).as_bytes());
+            request
+        };
+
+        for name in &["Upgrade", "Connection", "Sec-WebSocket-Key"] {
+            let (e, r) = receive(&without(name));
+            assert!(matches!(e, Error::HeaderNotFound(h) if h == *name));
+            assert!(matches!(r, Response::Reject { status_code: 400 }))
+        }
+        for &(name, value) in &[("Upgrade", "h2c"), ("Connection", "keep-alive"), ("Sec-WebSocket-Key", "c2hvcnQ=")] {
+            let (e, r) = receive(&replace(name, value));
+            assert!(matches!(e, Error::UnexpectedHeader(h) if h == name));
+            assert!(matches!(r, Response::Reject { status_code: 400 }))
+        }
+
+        for request in &[without("Sec-WebSocket-Version"), replace("Sec-WebSocket-Version", "8")] {
+            let (_, r) = receive(request);
+            let mut server = Server::new(Cursor::new(Vec::new()));
+            block_on(server.send_response(&r)).unwrap();
+            let output = server.into_inner().into_inner();
+            assert_eq! {
+                &b"HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\n\r\n"[..],
+                &output[..]
+            }
+        }
+    }
+}
@@ -117,12 +117,15 @@ pub mod extension;
 pub mod handshake;
 pub mod connection;
 
+#[cfg(test)]
+mod test_util;
+
 use bytes::BytesMut;
 use futures::io::{AsyncRead, AsyncReadExt};
 use std::io;
 
 pub use connection::{Mode, Receiver, Sender};
-pub use data::{Data, Incoming};
+pub use data::{Data, Incoming, Message, Outgoing};
 
 pub type BoxedError = Box<dyn std::error::Error + Send + Sync>;
 
@@ -0,0 +1,248 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Helpers shared by the unit tests of this crate.
+
+use crate::{BoxedError, Parsing, Storage, base::{Codec, Header}, extension::{Extension, Param}};
+use futures::{channel::mpsc, prelude::*};
+use std::{cell::RefCell, fmt, io, pin::Pin, sync::Once, task::{Context, Poll}};
+
+type DecodeHook = Box<dyn FnMut(&mut Header, &mut Vec<u8>) -> Result<(), BoxedError> + Send>;
+type EncodeHook = Box<dyn FnMut(&mut Header, &mut Storage) -> Result<(), BoxedError> + Send>;
+type ExtensionDataHook = Box<dyn FnMut(&Header, &[u8]) -> Result<(), BoxedError> + Send>;
+
+/// One end of an in-memory, bidirectional byte stream.
+///
+/// Bytes written to one end can be read from the other. Closing one end
+/// signals EOF to the other.
+#[derive(Debug)]
+pub(crate) struct Endpoint {
+    tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize
+}
+
+/// Create a connected pair of in-memory endpoints.
+pub(crate) fn duplex() -> (Endpoint, Endpoint) {
+    let (tx1, rx1) = mpsc::unbounded();
+    let (tx2, rx2) = mpsc::unbounded();
+    let a = Endpoint { tx: Some(tx1), rx: rx2, buf: Vec::new(), pos: 0 };
+    let b = Endpoint { tx: Some(tx2), rx: rx1, buf: Vec::new(), pos: 0 };
+    (a, b)
+}
+
+/// An extension which does nothing unless configured otherwise.
+///
+/// It is enabled, does not reserve any bits and passes all frames through.
+pub(crate) struct Stub {
+    name: &'static str,
+    enabled: bool,
+    reserved_bits: (bool, bool, bool),
+    extension_data_len: usize,
+    on_extension_data: Option<ExtensionDataHook>,
+    on_decode: Option<DecodeHook>,
+    on_encode: Option<EncodeHook>
+}
+
+impl Stub {
+    pub(crate) fn new(name: &'static str) -> Self {
+        Stub {
+            name,
+            enabled: true,
+            reserved_bits: (false, false, false),
+            extension_data_len: 0,
+            on_extension_data: None,
+            on_decode: None,
+            on_encode: None
+        }
+    }
+
+    /// Consider the extension disabled, e.g. to decline it during negotiation.
+    pub(crate) fn disabled(mut self) -> Self {
+        self.enabled = false;
+        self
+    }
+
+    pub(crate) fn reserved_bits(mut self, bits: (bool, bool, bool)) -> Self {
+        self.reserved_bits = bits;
+        self
+    }
+
+    /// Expect `len` bytes of extension data per frame and pass them to `f`.
+    pub(crate) fn extension_data<F>(mut self, len: usize, f: F) -> Self
+    where
+        F: FnMut(&Header, &[u8]) -> Result<(), BoxedError> + Send + 'static
+    {
+        self.extension_data_len = len;
+        self.on_extension_data = Some(Box::new(f));
+        self
+    }
+
+    pub(crate) fn on_decode<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&mut Header, &mut Vec<u8>) -> Result<(), BoxedError> + Send + 'static
+    {
+        self.on_decode = Some(Box::new(f));
+        self
+    }
+
+    pub(crate) fn on_encode<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&mut Header, &mut Storage) -> Result<(), BoxedError> + Send + 'static
+    {
+        self.on_encode = Some(Box::new(f));
+        self
+    }
+}
+
+impl fmt::Debug for Stub {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stub").field("name", &self.name).finish()
+    }
+}
+
+impl Extension for Stub {
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn params(&self) -> &[Param<'_>] {
+        &[]
+    }
+
+    fn configure(&mut self, _: &[Param]) -> Result<(), BoxedError> {
+        Ok(())
+    }
+
+    fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
+        self.on_encode.as_mut().map_or(Ok(()), |f| f(header, data))
+    }
+
+    fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+        self.on_decode.as_mut().map_or(Ok(()), |f| f(header, data))
+    }
+
+    fn extension_data_len(&self, _: &Header) -> usize {
+        self.extension_data_len
+    }
+
+    fn decode_extension_data(&mut self, header: &Header, data: &[u8]) -> Result<(), BoxedError> {
+        self.on_extension_data.as_mut().map_or(Ok(()), |f| f(header, data))
+    }
+
+    fn reserved_bits(&self) -> (bool, bool, bool) {
+        self.reserved_bits
+    }
+}
+
+/// A logger which records the messages logged by threads capturing logs.
+struct CaptureLogger;
+
+thread_local! {
+    static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        CAPTURED.with(|c| c.borrow().is_some())
+    }
+
+    fn log(&self, record: &log::Record) {
+        CAPTURED.with(|c| {
+            if let Some(messages) = c.borrow_mut().as_mut() {
+                messages.push(record.args().to_string())
+            }
+        })
+    }
+
+    fn flush(&self) {}
+}
+
+/// Run `f` and return the messages it logged on the current thread.
+///
+/// All log levels are captured. Messages of other threads, e.g. of tests
+/// running in parallel, are not.
+pub(crate) fn capture_logs(f: impl FnOnce()) -> Vec<String> {
+    static INIT: Once = Once::new();
+    static LOGGER: CaptureLogger = CaptureLogger;
+    INIT.call_once(|| {
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Trace)
+        }
+    });
+    CAPTURED.with(|c| *c.borrow_mut() = Some(Vec::new()));
+    f();
+    CAPTURED.with(|c| c.borrow_mut().take()).unwrap_or_default()
+}
+
+/// Read the next raw frame from the given endpoint and unmask its payload.
+pub(crate) async fn read_frame(endpoint: &mut Endpoint) -> (Header, Vec<u8>) {
+    let mut codec = Codec::new();
+    codec.add_reserved_bits((true, true, true));
+    let mut bytes = Vec::new();
+    let (header, offset) = loop {
+        match codec.decode_header(&bytes).unwrap() {
+            Parsing::Done { value, offset } => break (value, offset),
+            Parsing::NeedMore(n) => {
+                let i = bytes.len();
+                bytes.resize(i + n, 0);
+                endpoint.read_exact(&mut bytes[i ..]).await.unwrap()
+            }
+        }
+    };
+    let mut payload = vec![0; header.payload_len()];
+    debug_assert_eq!(offset, bytes.len());
+    endpoint.read_exact(&mut payload).await.unwrap();
+    Codec::apply_mask(&header, &mut payload);
+    (header, payload)
+}
+
+impl AsyncRead for Endpoint {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, out: &mut [u8]) -> Poll<io::Result<usize>> {
+        loop {
+            if self.pos < self.buf.len() {
+                let n = std::cmp::min(out.len(), self.buf.len() - self.pos);
+                out[.. n].copy_from_slice(&self.buf[self.pos .. self.pos + n]);
+                self.pos += n;
+                return Poll::Ready(Ok(n))
+            }
+            match futures::ready!(self.rx.poll_next_unpin(cx)) {
+                Some(chunk) => {
+                    self.buf = chunk;
+                    self.pos = 0
+                }
+                None => return Poll::Ready(Ok(0))
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Endpoint {
+    fn poll_write(mut self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if let Some(tx) = &mut self.tx {
+            if tx.unbounded_send(buf.to_vec()).is_ok() {
+                return Poll::Ready(Ok(buf.len()))
+            }
+        }
+        Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        self.tx = None;
+        Poll::Ready(Ok(()))
+    }
+}